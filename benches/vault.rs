@@ -0,0 +1,79 @@
+//! Timings of the basic operations on a data file with many entries.
+//! Run with `cargo bench`.
+
+use guarakapa::{codec, fman};
+use std::time::{Duration, Instant};
+
+const ENTRIES: usize = 10_000;
+const SAMPLES: usize = 100;
+const PASSWORD: &str = "bench-pass";
+
+fn entry(i: usize) -> fman::OpenEntry {
+    fman::OpenEntry {
+        desc: format!("description {}", i),
+        user: format!("user{}", i),
+        email: format!("user{}@example.com", i),
+        pw: format!("password-{}", i).into(),
+        ..Default::default()
+    }
+}
+
+fn report(operation: &str, total: Duration, count: usize) {
+    println!(
+        "{:<28} {:>10.3} ms total {:>10.3} ms/op",
+        operation,
+        total.as_secs_f64() * 1000.0,
+        total.as_secs_f64() * 1000.0 / count as f64
+    );
+}
+
+fn time<T>(f: impl FnOnce() -> T) -> (T, Duration) {
+    let start = Instant::now();
+    let result = f();
+    (result, start.elapsed())
+}
+
+fn main() {
+    let file = fman::File::try_new(PASSWORD).unwrap();
+    let mut vault = file.unlock(PASSWORD).unwrap();
+
+    let (_, elapsed) = time(|| {
+        for i in 0..ENTRIES {
+            vault.add(format!("entry{}", i), entry(i)).unwrap();
+        }
+    });
+    report(&format!("add ({} entries)", ENTRIES), elapsed, ENTRIES);
+
+    let (contents, elapsed) = time(|| codec::encode(vault.seal().unwrap()).unwrap());
+    report("seal and encode", elapsed, 1);
+
+    let (file, elapsed) = time(|| codec::decode::<fman::File>(&contents).unwrap());
+    report("decode", elapsed, 1);
+
+    let (mut vault, elapsed) = time(|| file.unlock(PASSWORD).unwrap());
+    report("unlock", elapsed, 1);
+
+    let (names, elapsed) = time(|| vault.list());
+    assert_eq!(names.len(), ENTRIES);
+    report("list", elapsed, 1);
+
+    let (_, elapsed) = time(|| {
+        for i in (0..ENTRIES).step_by(ENTRIES / SAMPLES) {
+            let name = format!("entry{}", i);
+            assert!(vault.get(&name).unwrap().is_some());
+        }
+    });
+    report("get", elapsed, SAMPLES);
+
+    let (_, elapsed) = time(|| {
+        for i in (0..ENTRIES).step_by(ENTRIES / SAMPLES) {
+            let name = format!("entry{}", i);
+            vault.remove(&name).unwrap();
+        }
+    });
+    report("remove", elapsed, SAMPLES);
+
+    let (entries, elapsed) = time(|| vault.get_all().unwrap());
+    assert_eq!(entries.len(), ENTRIES - SAMPLES);
+    report("decrypt all entries", elapsed, 1);
+}
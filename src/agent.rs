@@ -0,0 +1,405 @@
+//! Agent keeping the keys of unlocked data files in memory for a while, so
+//! that the master password does not have to be typed for every command (in
+//! the spirit of `ssh-agent`).
+//!
+//! The agent listens on a Unix socket under `$XDG_RUNTIME_DIR` that only the
+//! user can access, and rejects connections from processes of other users.
+//! A key is forgotten after it is not used for the idle timeout, or when the
+//! agent is locked. Messages are bincode-serialized and prefixed by their
+//! length.
+//!
+//! The agent hands the key of an unlocked file to any process of the user
+//! that asks for it with a copy of the file: while a file is unlocked, every
+//! program running as the user can decrypt all of its entries without the
+//! master password.
+
+use crate::{
+    codec,
+    crypto::{SecretBytes, SecretString},
+    fman::{self, Factors, File},
+    fs,
+};
+use anyhow::{bail, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::os::unix::{
+    fs::{DirBuilderExt, PermissionsExt},
+    io::AsRawFd,
+    net::{UnixListener, UnixStream},
+};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const SOCKET_ENV: &str = "GUARAKAPA_AGENT_SOCK";
+const SOCKET_NAME: &str = "agent.sock";
+
+/// Time after which a key not used is forgotten, unless set otherwise
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+/// Messages longer than this are rejected
+const MAX_MESSAGE_LEN: usize = 1 << 28;
+
+/// Clients taking longer than this to send or read a message are dropped
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often the agent wipes expired keys
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Deserialize)]
+pub enum Request {
+    /// Checks the master password (and the other factors it requires) of an
+    /// (encoded) data file and keeps its key
+    Unlock {
+        file: Vec<u8>,
+        pw: SecretString,
+        factors: Factors,
+    },
+    /// Asks for the key of an (encoded) data file unlocked before. The whole
+    /// key is given to the client, which is trusted as it runs as the user.
+    Open { file: Vec<u8> },
+    /// Forgets all keys
+    Lock,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Response {
+    /// The key was kept, and will be forgotten after `timeout` seconds idle
+    Unlocked {
+        timeout: u64,
+    },
+    /// Key of the requested file
+    Key(SecretBytes),
+    /// The requested file was not unlocked, or its key expired
+    NotUnlocked,
+    /// All keys were forgotten
+    Locked,
+    Error(String),
+}
+
+struct CachedKey {
+    key: SecretBytes,
+    last_used: Instant,
+}
+
+/// Keys of the unlocked files, by the salt of the file
+type Keys = HashMap<[u8; 16], CachedKey>;
+
+pub struct Agent {
+    listener: UnixListener,
+    path: PathBuf,
+    timeout: Duration,
+    keys: Arc<Mutex<Keys>>,
+}
+
+/// Path of the agent socket: `$GUARAKAPA_AGENT_SOCK` if set, otherwise
+/// `agent.sock` in the runtime directory (see `fs::runtime_dir`).
+pub fn socket_path() -> Result<PathBuf> {
+    match std::env::var_os(SOCKET_ENV) {
+        Some(path) => Ok(PathBuf::from(path)),
+        None => Ok(fs::runtime_dir()?.join(SOCKET_NAME)),
+    }
+}
+
+impl Agent {
+    /// Creates the socket at `path`, readable and writable only by the user.
+    /// Fails if another agent is already listening on it.
+    pub fn bind(path: &Path, timeout: Duration) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(dir)?;
+        }
+
+        if path.exists() {
+            if UnixStream::connect(path).is_ok() {
+                bail!("An agent is already listening on `{}`.", path.display());
+            }
+            // left behind by an agent that did not exit cleanly
+            std::fs::remove_file(path)?;
+        }
+
+        // the mask keeps the socket private between its creation and the
+        // change of permissions below
+        // SAFETY: `umask` only changes the file mode mask of the process
+        let mask = unsafe { libc::umask(0o177) };
+        let listener = UnixListener::bind(path);
+        unsafe { libc::umask(mask) };
+        let listener = listener?;
+
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+
+        Ok(Agent {
+            listener,
+            path: path.to_path_buf(),
+            timeout,
+            keys: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    /// Serves requests until the process is terminated.
+    pub fn run(&self) -> Result<()> {
+        let keys = Arc::clone(&self.keys);
+        let timeout = self.timeout;
+
+        std::thread::spawn(move || loop {
+            std::thread::sleep(EXPIRY_INTERVAL);
+            expire(&keys, timeout);
+        });
+
+        for stream in self.listener.incoming() {
+            // a misbehaving client must not stop the agent
+            if let Err(e) = stream.map_err(Into::into).and_then(|s| self.serve(s)) {
+                eprintln!("Request failed: {}", e);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn serve(&self, mut stream: UnixStream) -> Result<()> {
+        check_peer(&stream)?;
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+
+        let request = receive(&mut stream)?;
+        let response = self.handle(request);
+        send(&mut stream, &response)
+    }
+
+    fn handle(&self, request: Request) -> Response {
+        expire(&self.keys, self.timeout);
+        let mut keys = self.keys.lock().unwrap();
+
+        let result = match request {
+            Request::Unlock { file, pw, factors } => decode(&file)
+                .and_then(|file| {
+                    let salt = file.head.salt;
+                    Ok((salt, file.unlock_with_factors(&pw, &factors)?))
+                })
+                .map(|(salt, vault)| {
+                    keys.insert(
+                        salt,
+                        CachedKey {
+                            key: vault.key().clone(),
+                            last_used: Instant::now(),
+                        },
+                    );
+                    Response::Unlocked {
+                        timeout: self.timeout.as_secs(),
+                    }
+                }),
+            Request::Open { file } => decode(&file).and_then(|file| {
+                match keys.get_mut(&file.head.salt) {
+                    Some(cached) => {
+                        // the key must still match (the file can have been
+                        // replaced by another with the same salt)
+                        file.unlock_with_key(cached.key.clone())?;
+                        cached.last_used = Instant::now();
+                        Ok(Response::Key(cached.key.clone()))
+                    }
+                    None => Ok(Response::NotUnlocked),
+                }
+            }),
+            Request::Lock => {
+                keys.clear();
+                Ok(Response::Locked)
+            }
+        };
+
+        result.unwrap_or_else(|e| Response::Error(e.to_string()))
+    }
+}
+
+impl Drop for Agent {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn decode(file: &[u8]) -> Result<File> {
+    Ok(codec::decode(file)?)
+}
+
+/// Drops (and so wipes) the keys not used for `timeout`
+fn expire(keys: &Mutex<Keys>, timeout: Duration) {
+    keys.lock()
+        .unwrap()
+        .retain(|_, cached| cached.last_used.elapsed() < timeout);
+}
+
+/// Sends `request` to the agent listening on `path` and waits for its
+/// response.
+pub fn request(path: &Path, request: &Request) -> Result<Response> {
+    let mut stream = UnixStream::connect(path)?;
+    // the socket could have been replaced by a process of another user
+    check_peer(&stream)?;
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+
+    send(&mut stream, request)?;
+    receive(&mut stream)
+}
+
+/// Fails unless the process on the other end of `stream` belongs to the
+/// same user as this one.
+fn check_peer(stream: &UnixStream) -> Result<()> {
+    let uid = peer_uid(stream)?;
+
+    // SAFETY: `geteuid` has no preconditions and cannot fail
+    if uid != unsafe { libc::geteuid() } {
+        bail!("Connection from a process of another user (uid {}).", uid);
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn peer_uid(stream: &UnixStream) -> Result<libc::uid_t> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+
+    // SAFETY: `cred` and `len` are valid for writes of the given size
+    let res = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+
+    if res != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    Ok(cred.uid)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn peer_uid(stream: &UnixStream) -> Result<libc::uid_t> {
+    let mut uid = 0;
+    let mut gid = 0;
+
+    // SAFETY: `uid` and `gid` are valid for writes
+    if unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    Ok(uid)
+}
+
+fn send<T: Serialize>(stream: &mut UnixStream, message: &T) -> Result<()> {
+    let bytes = fman::serialize_secret(message)?;
+    stream.write_all(&(bytes.len() as u32).to_le_bytes())?;
+    stream.write_all(&bytes)?;
+    Ok(())
+}
+
+fn receive<T: DeserializeOwned>(stream: &mut UnixStream) -> Result<T> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as usize;
+
+    if len > MAX_MESSAGE_LEN {
+        bail!("Message too long ({} bytes).", len);
+    }
+
+    let mut bytes = SecretBytes::zeroed(len);
+    stream.read_exact(&mut bytes)?;
+    Ok(bincode::deserialize(&bytes)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PW: &str = "master-password";
+
+    fn start_agent(name: &str, timeout: Duration) -> PathBuf {
+        let path = std::env::temp_dir()
+            .join(format!("gk-agent-{}", std::process::id()))
+            .join(name);
+        let agent = Agent::bind(&path, timeout).unwrap();
+        std::thread::spawn(move || agent.run());
+        path
+    }
+
+    fn encoded_file() -> Vec<u8> {
+        codec::encode(&File::try_new(PW).unwrap()).unwrap()
+    }
+
+    fn unlock(path: &Path, file: &[u8], pw: &str) -> Response {
+        let request = Request::Unlock {
+            file: file.to_vec(),
+            pw: pw.into(),
+            factors: Factors::default(),
+        };
+        super::request(path, &request).unwrap()
+    }
+
+    fn open(path: &Path, file: &[u8]) -> Response {
+        let request = Request::Open {
+            file: file.to_vec(),
+        };
+        super::request(path, &request).unwrap()
+    }
+
+    #[test]
+    fn serves_keys_of_unlocked_files() {
+        let path = start_agent("serves.sock", DEFAULT_TIMEOUT);
+        let file = encoded_file();
+
+        assert!(matches!(open(&path, &file), Response::NotUnlocked));
+        assert!(matches!(unlock(&path, &file, "wrong"), Response::Error(_)));
+        assert!(matches!(
+            unlock(&path, &file, PW),
+            Response::Unlocked { .. }
+        ));
+
+        match open(&path, &file) {
+            Response::Key(key) => assert!(decode(&file).unwrap().unlock_with_key(key).is_ok()),
+            response => panic!("unexpected response: {:?}", response),
+        }
+
+        // another file is not unlocked by the same agent
+        assert!(matches!(
+            open(&path, &encoded_file()),
+            Response::NotUnlocked
+        ));
+
+        assert!(matches!(
+            super::request(&path, &Request::Lock).unwrap(),
+            Response::Locked
+        ));
+        assert!(matches!(open(&path, &file), Response::NotUnlocked));
+    }
+
+    #[test]
+    fn keys_expire_after_idle_timeout() {
+        let path = start_agent("expires.sock", Duration::from_millis(200));
+        let file = encoded_file();
+
+        unlock(&path, &file, PW);
+        assert!(matches!(open(&path, &file), Response::Key(_)));
+
+        std::thread::sleep(Duration::from_millis(300));
+        assert!(matches!(open(&path, &file), Response::NotUnlocked));
+    }
+
+    #[test]
+    fn socket_is_private() {
+        let path = start_agent("private.sock", DEFAULT_TIMEOUT);
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        assert!(Agent::bind(&path, DEFAULT_TIMEOUT).is_err());
+    }
+}
@@ -0,0 +1,384 @@
+//! Checks the decrypted entries of a data file for weak, reused and old
+//! passwords.
+
+use crate::{fman::OpenEntry, time};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+const COMMON_PASSWORDS: &[&str] = &[
+    "password", "passw0rd", "qwerty", "letmein", "welcome", "admin", "login", "master", "secret",
+    "dragon", "monkey", "iloveyou", "sunshine", "princess", "football", "baseball", "shadow",
+    "superman", "trustno1", "hello", "freedom", "whatever", "starwars", "computer", "abc123",
+    "changeme",
+];
+
+const KEYBOARD_ROWS: &[&str] = &[
+    "`1234567890-=",
+    "qwertyuiop[]\\",
+    "asdfghjkl;'",
+    "zxcvbnm,./",
+];
+
+/// Minimum length of a sequence, repetition or keyboard walk to be
+/// considered a pattern.
+const MIN_PATTERN_LEN: usize = 3;
+
+/// Thresholds used to flag passwords
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Passwords not changed for at least this many days are reported
+    pub max_age_days: u64,
+    /// Passwords shorter than this are reported
+    pub min_length: usize,
+    /// Passwords with a lower estimated entropy are reported
+    pub min_entropy_bits: f64,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            max_age_days: 365,
+            min_length: 10,
+            min_entropy_bits: 50.0,
+        }
+    }
+}
+
+/// Problem found with the password of an entry
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Issue {
+    /// The entry has no password
+    Empty,
+    /// The same password is used by the listed entries
+    Reused { with: Vec<String> },
+    /// The password is shorter than the minimum length
+    Short { length: usize },
+    /// The password is easy to guess
+    Weak { entropy_bits: f64 },
+    /// The password contains the entry name
+    MatchesName,
+    /// The password contains the user name or e-mail of the entry
+    MatchesUser,
+    /// The password was not changed in `days` days (`None` if unknown)
+    Stale { days: Option<u64> },
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Finding {
+    pub entry: String,
+    #[serde(flatten)]
+    pub issue: Issue,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Report {
+    pub entries_checked: usize,
+    pub findings: Vec<Finding>,
+}
+
+/// Audits the passwords of `entries` (pairs of entry name and contents).
+/// `now` is the reference time to check the age of passwords.
+pub fn audit(entries: &[(String, OpenEntry)], options: &Options, now: u64) -> Report {
+    let mut by_password: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+
+    for (name, entry) in entries {
+        if !entry.pw.is_empty() {
+            by_password.entry(&entry.pw).or_default().push(name);
+        }
+    }
+
+    let mut findings = Vec::new();
+    let mut report = |entry: &str, issue| {
+        findings.push(Finding {
+            entry: entry.to_string(),
+            issue,
+        })
+    };
+
+    for (name, entry) in entries {
+        let pw: &str = &entry.pw;
+
+        if pw.is_empty() {
+            report(name, Issue::Empty);
+        } else {
+            let users = &by_password[pw];
+
+            if users.len() > 1 {
+                let with = users
+                    .iter()
+                    .filter(|&&other| other != name)
+                    .map(|other| other.to_string())
+                    .collect();
+                report(name, Issue::Reused { with });
+            }
+
+            let length = pw.chars().count();
+            if length < options.min_length {
+                report(name, Issue::Short { length });
+            }
+
+            let entropy_bits = estimate_entropy(pw);
+            if entropy_bits < options.min_entropy_bits {
+                report(name, Issue::Weak { entropy_bits });
+            }
+
+            if contains_ignore_case(pw, name) {
+                report(name, Issue::MatchesName);
+            }
+
+            let email_user = entry.email.split('@').next().unwrap_or("");
+            if contains_ignore_case(pw, &entry.user) || contains_ignore_case(pw, email_user) {
+                report(name, Issue::MatchesUser);
+            }
+        }
+
+        if entry.pw_changed == 0 {
+            report(name, Issue::Stale { days: None });
+        } else {
+            let days = time::days_since(entry.pw_changed, now);
+            if days >= options.max_age_days {
+                report(name, Issue::Stale { days: Some(days) });
+            }
+        }
+    }
+
+    Report {
+        entries_checked: entries.len(),
+        findings,
+    }
+}
+
+fn contains_ignore_case(haystack: &str, needle: &str) -> bool {
+    needle.chars().count() >= MIN_PATTERN_LEN
+        && haystack.to_lowercase().contains(&needle.to_lowercase())
+}
+
+/// Estimates the entropy of a password in bits, in the spirit of `zxcvbn`:
+/// the password is split into the cheapest sequence of patterns (common
+/// passwords, years, repetitions, sequences and keyboard walks), and the
+/// remaining characters are considered random among the character classes
+/// used in the password.
+pub fn estimate_entropy(pw: &str) -> f64 {
+    let chars: Vec<char> = pw.chars().collect();
+    let lower: Vec<char> = chars.iter().map(char::to_ascii_lowercase).collect();
+    let char_bits = (pool_size(&chars) as f64).log2();
+
+    // cost[i]: minimum number of bits to guess the first i characters
+    let mut cost = vec![f64::INFINITY; chars.len() + 1];
+    cost[0] = 0.0;
+
+    for start in 0..chars.len() {
+        let base = cost[start];
+
+        if base + char_bits < cost[start + 1] {
+            cost[start + 1] = base + char_bits;
+        }
+
+        for (len, bits) in patterns_at(&chars, &lower, start) {
+            let end = start + len;
+            if base + bits < cost[end] {
+                cost[end] = base + bits;
+            }
+        }
+    }
+
+    cost[chars.len()]
+}
+
+/// Size of the set of characters an attacker would need to try for a
+/// password using the same character classes as `chars`.
+fn pool_size(chars: &[char]) -> usize {
+    let has = |f: fn(&char) -> bool| chars.iter().any(f);
+    let mut size = 0;
+
+    if has(char::is_ascii_lowercase) {
+        size += 26;
+    }
+    if has(char::is_ascii_uppercase) {
+        size += 26;
+    }
+    if has(char::is_ascii_digit) {
+        size += 10;
+    }
+    if has(char::is_ascii_punctuation) || has(|c| *c == ' ') {
+        size += 33;
+    }
+    if has(|c| !c.is_ascii()) {
+        size += 100;
+    }
+
+    size.max(1)
+}
+
+/// Patterns starting at `start`, as pairs of length and guessing cost in bits
+fn patterns_at(chars: &[char], lower: &[char], start: usize) -> Vec<(usize, f64)> {
+    let mut patterns = Vec::new();
+    let rest: String = lower[start..].iter().collect();
+
+    for (rank, word) in COMMON_PASSWORDS.iter().enumerate() {
+        if rest.starts_with(word) {
+            let case_bits = if chars[start..start + word.len()]
+                .iter()
+                .any(char::is_ascii_uppercase)
+            {
+                1.0
+            } else {
+                0.0
+            };
+            patterns.push((word.len(), ((rank + 2) as f64).log2() + case_bits));
+        }
+    }
+
+    if let Some(year) = rest.get(..4).and_then(|s| s.parse::<u32>().ok()) {
+        if (1900..2100).contains(&year) {
+            patterns.push((4, 200_f64.log2()));
+        }
+    }
+
+    let repeated = lower[start..]
+        .iter()
+        .take_while(|&&c| c == lower[start])
+        .count();
+    if repeated >= MIN_PATTERN_LEN {
+        let bits = (pool_size(&chars[start..start + 1]) as f64 * repeated as f64).log2();
+        patterns.push((repeated, bits));
+    }
+
+    for step in [1, -1] {
+        let len = run_length(lower, start, |a, b| b as i64 - a as i64 == step);
+        if len >= MIN_PATTERN_LEN {
+            patterns.push((len, (26.0 * 2.0 * len as f64).log2()));
+        }
+    }
+
+    let walk = run_length(lower, start, are_adjacent_keys);
+    if walk >= MIN_PATTERN_LEN {
+        patterns.push((walk, (47.0 * 2.0 * walk as f64).log2()));
+    }
+
+    patterns
+}
+
+/// Length of the longest run starting at `start` in which every pair of
+/// consecutive characters satisfies `linked`.
+fn run_length(chars: &[char], start: usize, linked: impl Fn(char, char) -> bool) -> usize {
+    1 + chars[start..]
+        .windows(2)
+        .take_while(|pair| linked(pair[0], pair[1]))
+        .count()
+}
+
+fn are_adjacent_keys(a: char, b: char) -> bool {
+    KEYBOARD_ROWS.iter().any(|row| {
+        let row: Vec<char> = row.chars().collect();
+        row.windows(2)
+            .any(|pair| (pair[0] == a && pair[1] == b) || (pair[0] == b && pair[1] == a))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(pw: &str, user: &str, pw_changed: u64) -> OpenEntry {
+        OpenEntry {
+            pw: pw.into(),
+            user: user.to_string(),
+            pw_changed,
+            ..Default::default()
+        }
+    }
+
+    fn issues_of(report: &Report, name: &str) -> Vec<Issue> {
+        report
+            .findings
+            .iter()
+            .filter(|finding| finding.entry == name)
+            .map(|finding| finding.issue.clone())
+            .collect()
+    }
+
+    #[test]
+    fn guessable_passwords_have_low_entropy() {
+        for pw in [
+            "password",
+            "Password1990",
+            "aaaaaaaaaaaaaaaa",
+            "abcdefghijkl",
+            "qwertyuiop123",
+        ] {
+            assert!(estimate_entropy(pw) < 40.0, "{} is not weak", pw);
+        }
+    }
+
+    #[test]
+    fn random_passwords_have_high_entropy() {
+        for pw in ["x7#Rk!2qLp9$Vz", "correct horse battery staple"] {
+            assert!(estimate_entropy(pw) > 60.0, "{} is not strong", pw);
+        }
+    }
+
+    #[test]
+    fn finds_reused_and_empty_passwords() {
+        let now = time::now();
+        let entries = vec![
+            ("a".to_string(), entry("x7#Rk!2qLp9$Vz", "", now)),
+            ("b".to_string(), entry("x7#Rk!2qLp9$Vz", "", now)),
+            ("c".to_string(), entry("", "", now)),
+        ];
+        let report = audit(&entries, &Options::default(), now);
+
+        assert_eq!(report.entries_checked, 3);
+        assert_eq!(
+            issues_of(&report, "a"),
+            vec![Issue::Reused {
+                with: vec!["b".to_string()]
+            }]
+        );
+        assert_eq!(issues_of(&report, "c"), vec![Issue::Empty]);
+    }
+
+    #[test]
+    fn finds_passwords_matching_names() {
+        let now = time::now();
+        let entries = vec![
+            ("github".to_string(), entry("my-GitHub-x7#Rk!2q", "", now)),
+            (
+                "mail".to_string(),
+                entry("johndoe-x7#Rk!2qLp", "JohnDoe", now),
+            ),
+        ];
+        let report = audit(&entries, &Options::default(), now);
+
+        assert_eq!(issues_of(&report, "github"), vec![Issue::MatchesName]);
+        assert_eq!(issues_of(&report, "mail"), vec![Issue::MatchesUser]);
+    }
+
+    #[test]
+    fn finds_old_passwords() {
+        let now = 1000 * time::SECS_PER_DAY;
+        let entries = vec![
+            (
+                "old".to_string(),
+                entry("x7#Rk!2qLp9$Vz", "", now - 400 * time::SECS_PER_DAY),
+            ),
+            (
+                "new".to_string(),
+                entry("y7#Rk!2qLp9$Vz", "", now - 10 * time::SECS_PER_DAY),
+            ),
+            ("unknown".to_string(), entry("z7#Rk!2qLp9$Vz", "", 0)),
+        ];
+        let report = audit(&entries, &Options::default(), now);
+
+        assert_eq!(
+            issues_of(&report, "old"),
+            vec![Issue::Stale { days: Some(400) }]
+        );
+        assert_eq!(issues_of(&report, "new"), vec![]);
+        assert_eq!(
+            issues_of(&report, "unknown"),
+            vec![Issue::Stale { days: None }]
+        );
+    }
+}
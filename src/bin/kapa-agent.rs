@@ -0,0 +1,57 @@
+//! Keeps the keys of data files unlocked with `kapa unlock` in memory, so
+//! that other commands do not ask for the master password.
+
+use guarakapa::agent::{self, Agent};
+use std::time::Duration;
+
+fn show_help(exec_name: &str) {
+    println!(
+        "Usage: {exec} [--timeout SECONDS]\n\n\
+    Keeps the keys of data files unlocked with `kapa unlock` until they are \
+    not used for `SECONDS` (default: {timeout}) or `kapa lock` is run. The \
+    agent listens on `$XDG_RUNTIME_DIR/{program}/agent.sock` (or \
+    `$GUARAKAPA_AGENT_SOCK`) and runs until it is terminated.\n\n\
+    The keys are given to any program running as your user, which can then \
+    read all the entries of the unlocked data files without the master \
+    password.",
+        exec = exec_name,
+        timeout = agent::DEFAULT_TIMEOUT.as_secs(),
+        program = env!("CARGO_PKG_NAME")
+    );
+}
+
+fn run(timeout: Duration) -> anyhow::Result<()> {
+    let path = agent::socket_path()?;
+    let agent = Agent::bind(&path, timeout)?;
+    println!("Agent listening on {}", path.display());
+    agent.run()
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let params: Vec<&str> = args[1..].iter().map(String::as_str).collect();
+
+    let timeout = match params.as_slice() {
+        [] => agent::DEFAULT_TIMEOUT,
+        ["--timeout", secs] => match secs.parse() {
+            Ok(secs) if secs > 0 => Duration::from_secs(secs),
+            _ => {
+                show_help(&args[0]);
+                std::process::exit(1);
+            }
+        },
+        ["version" | "--version" | "-v"] => {
+            println!("{}", env!("CARGO_PKG_VERSION"));
+            return;
+        }
+        _ => {
+            show_help(&args[0]);
+            return;
+        }
+    };
+
+    if let Err(e) = run(timeout) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
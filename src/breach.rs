@@ -0,0 +1,220 @@
+//! Offline lookup of passwords in a local copy of the Have I Been Pwned
+//! password database. Passwords are never sent anywhere: they are hashed
+//! with SHA-1 and searched for in files downloaded beforehand.
+//!
+//! Two layouts are supported:
+//! * a single file with one `HASH:COUNT` line per password, sorted by hash
+//!   (as distributed by HIBP). It is binary-searched, so it is never loaded
+//!   into memory;
+//! * a directory of range files, as served by the k-anonymity API: each file
+//!   is named after the first 5 hex digits of the hashes it contains (e.g.
+//!   `21BD1` or `21BD1.txt`) and has one `SUFFIX:COUNT` line per password.
+
+use anyhow::{bail, Result};
+use std::io::{BufRead, BufReader, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+const PREFIX_LEN: usize = 5;
+const HASH_LEN: usize = 40;
+
+pub enum Database {
+    Sorted(PathBuf),
+    Ranges(PathBuf),
+}
+
+impl Database {
+    /// Opens the database at `path`, which can be either a sorted hash file
+    /// or a directory of range files.
+    pub fn open(path: &str) -> Result<Self> {
+        let path = Path::new(path);
+
+        if path.is_dir() {
+            Ok(Database::Ranges(path.to_path_buf()))
+        } else if path.is_file() {
+            Ok(Database::Sorted(path.to_path_buf()))
+        } else {
+            bail!("Breach database `{}` not found.", path.display());
+        }
+    }
+
+    /// Number of times `password` appears in the breaches (0 if never).
+    pub fn count(&self, password: &str) -> Result<u64> {
+        let hash = sha1_hex(password);
+
+        match self {
+            Database::Sorted(path) => search_sorted(path, &hash),
+            Database::Ranges(dir) => search_range(dir, &hash),
+        }
+    }
+}
+
+/// SHA-1 hash of a password as upper case hex, as used by HIBP
+pub fn sha1_hex(password: &str) -> String {
+    openssl::sha::sha1(password.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect()
+}
+
+/// Splits a `HASH:COUNT` line into the upper case hash and the count
+fn parse_line(line: &str) -> Option<(String, u64)> {
+    let (hash, count) = line.trim_end().split_once(':')?;
+    Some((hash.to_ascii_uppercase(), count.trim().parse().ok()?))
+}
+
+fn search_range(dir: &Path, hash: &str) -> Result<u64> {
+    let (prefix, suffix) = hash.split_at(PREFIX_LEN);
+
+    let path = [prefix.to_string(), format!("{}.txt", prefix)]
+        .iter()
+        .map(|name| dir.join(name))
+        .find(|path| path.is_file());
+
+    let path = match path {
+        Some(path) => path,
+        None => bail!(
+            "Range file for prefix `{}` not found in `{}`.",
+            prefix,
+            dir.display()
+        ),
+    };
+
+    for line in BufReader::new(std::fs::File::open(path)?).lines() {
+        if let Some((line_suffix, count)) = parse_line(&line?) {
+            if line_suffix == suffix {
+                return Ok(count);
+            }
+        }
+    }
+
+    Ok(0)
+}
+
+/// Binary search over the byte offsets of the file. Each probe reads the
+/// first full line starting at or after the probed offset.
+fn search_sorted(path: &Path, hash: &str) -> Result<u64> {
+    let mut reader = BufReader::new(std::fs::File::open(path)?);
+    let len = reader.seek(SeekFrom::End(0))?;
+
+    let mut low = 0;
+    let mut high = len;
+
+    while low < high {
+        let mid = low + (high - low) / 2;
+
+        match line_after(&mut reader, mid)? {
+            Some(line) if parse_hash(&line).as_str() < hash => low = mid + 1,
+            _ => high = mid,
+        }
+    }
+
+    match line_after(&mut reader, low)? {
+        Some(line) => match parse_line(&line) {
+            Some((line_hash, count)) if line_hash == hash => Ok(count),
+            _ => Ok(0),
+        },
+        None => Ok(0),
+    }
+}
+
+fn parse_hash(line: &str) -> String {
+    line.get(..HASH_LEN).unwrap_or(line).to_ascii_uppercase()
+}
+
+/// Reads the first full line starting at or after `offset`, if any
+fn line_after<R: BufRead + Seek>(reader: &mut R, offset: u64) -> Result<Option<String>> {
+    let mut line = String::new();
+
+    if offset > 0 {
+        // the previous byte tells whether `offset` is at the start of a line
+        reader.seek(SeekFrom::Start(offset - 1))?;
+        reader.read_line(&mut line)?;
+        line.clear();
+    } else {
+        reader.seek(SeekFrom::Start(0))?;
+    }
+
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+
+    Ok(Some(line))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BREACHED: [(&str, u64); 3] = [
+        ("password", 9_545_824),
+        ("123456", 37_359_195),
+        ("qwerty", 3_946_737),
+    ];
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("gk-breach-{}-{}", std::process::id(), name))
+    }
+
+    fn write_sorted_db(path: &Path) {
+        let mut lines: Vec<String> = (0..200)
+            .map(|i| format!("{}:{}", sha1_hex(&format!("filler{}", i)), i + 1))
+            .chain(
+                BREACHED
+                    .iter()
+                    .map(|(pw, count)| format!("{}:{}", sha1_hex(pw), count)),
+            )
+            .collect();
+        lines.sort();
+        std::fs::write(path, lines.join("\r\n") + "\r\n").unwrap();
+    }
+
+    #[test]
+    fn sha1_matches_known_value() {
+        assert_eq!(
+            sha1_hex("password"),
+            "5BAA61E4C9B93F3F0682250B6CF8331B7EE68FD8"
+        );
+    }
+
+    #[test]
+    fn finds_passwords_in_sorted_file() {
+        let path = temp_path("sorted.txt");
+        write_sorted_db(&path);
+        let db = Database::open(path.to_str().unwrap()).unwrap();
+
+        for (pw, count) in BREACHED {
+            assert_eq!(db.count(pw).unwrap(), count);
+        }
+        for i in [0, 57, 199] {
+            assert_eq!(db.count(&format!("filler{}", i)).unwrap(), i + 1);
+        }
+        assert_eq!(db.count("x7#Rk!2qLp9$Vz").unwrap(), 0);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn finds_passwords_in_range_files() {
+        let dir = temp_path("ranges");
+        std::fs::create_dir_all(&dir).unwrap();
+
+        for (pw, count) in BREACHED {
+            let hash = sha1_hex(pw);
+            let (prefix, suffix) = hash.split_at(PREFIX_LEN);
+            let contents = format!(
+                "0000000000000000000000000000000000A:1\r\n{}:{}\r\n",
+                suffix, count
+            );
+            std::fs::write(dir.join(format!("{}.txt", prefix)), contents).unwrap();
+        }
+
+        let db = Database::open(dir.to_str().unwrap()).unwrap();
+
+        for (pw, count) in BREACHED {
+            assert_eq!(db.count(pw).unwrap(), count);
+        }
+        assert!(db.count("x7#Rk!2qLp9$Vz").is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
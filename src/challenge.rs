@@ -0,0 +1,64 @@
+//! Challenge-response through an external command, e.g. one that asks a
+//! hardware key for the HMAC of the challenge (`ykchalresp -2 -H -i-`).
+//!
+//! The command is run by `sh`, gets the challenge as hexadecimal on a line of
+//! its standard input, and must print the response on its standard output.
+//! The response is mixed into the key of the data file (see
+//! `fman::UnlockedVault::set_challenge`), and a new challenge is set every
+//! time the file is saved, so a response seen once cannot unlock later
+//! versions of the file.
+
+use crate::crypto::SecretBytes;
+use anyhow::{bail, Context, Result};
+use std::io::Write;
+use std::process::{Command, Stdio};
+use zeroize::Zeroize;
+
+/// Response of `command` to `challenge`: its output, without surrounding
+/// whitespace.
+pub fn respond(command: &str, challenge: &[u8]) -> Result<SecretBytes> {
+    let mut child = Command::new("sh")
+        .args(["-c", command])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Could not run the challenge command `{}`", command))?;
+
+    let hex: String = challenge
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(format!("{}\n", hex).as_bytes())?;
+    }
+
+    let mut output = child.wait_with_output()?;
+    let response = SecretBytes::from(output.stdout.trim_ascii());
+    output.stdout.zeroize();
+
+    if !output.status.success() {
+        bail!("Challenge command `{}` failed.", command);
+    }
+    if response.is_empty() {
+        bail!("Challenge command `{}` gave no response.", command);
+    }
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn response_is_the_output_of_the_command() {
+        let response = respond("cat", &[0, 1, 254, 255]).unwrap();
+        assert_eq!(&response[..], b"0001feff");
+    }
+
+    #[test]
+    fn failing_commands_give_no_response() {
+        assert!(respond("cat; false", &[1, 2]).is_err());
+        assert!(respond("cat > /dev/null", &[1, 2]).is_err());
+    }
+}
@@ -2,7 +2,7 @@
 //! For now, this metadata is just information about the program version and a
 //! magic number to recognise the file as valid.
 
-use anyhow::Result;
+use crate::error::{Error, Result};
 use serde::{Deserialize, Serialize};
 
 const VERSION_PARTS: usize = 3;
@@ -11,6 +11,20 @@ const VERSION_SEP: char = '.';
 /// Last version that did not track the program version in the data file
 const LAST_NONTRACKING_VERSION: &str = "0.8.5";
 
+pub type Version = [u8; VERSION_PARTS];
+
+/// Data types whose layout in the data file changed between program
+/// versions. Since the data is not self-describing, older layouts are
+/// selected by the program version recorded in the file.
+pub trait Versioned: Sized {
+    /// First program version that wrote the current layout
+    const MIN_VERSION: Version;
+
+    /// Decodes data written with an older layout. `version` is `None` for
+    /// files that did not track the program version.
+    fn decode_legacy(version: Option<Version>, data: &[u8]) -> Result<Self>;
+}
+
 pub fn encode(data: &impl Serialize) -> Result<Vec<u8>> {
     let mut file_bytes = bincode::serialize(data)?;
     let mut bytes = get_version_bytes();
@@ -22,20 +36,40 @@ pub fn encode(data: &impl Serialize) -> Result<Vec<u8>> {
 
 pub fn decode<'a, T>(content: &'a [u8]) -> Result<T>
 where
-    T: Deserialize<'a>,
+    T: Deserialize<'a> + Versioned,
 {
     let signature = get_signature_bytes();
 
     let file = if has_signature(content, &signature) {
-        bincode::deserialize(&content[VERSION_PARTS + signature.len()..])?
+        let data = &content[VERSION_PARTS + signature.len()..];
+        let version = read_version(content);
+
+        if version < T::MIN_VERSION {
+            T::decode_legacy(Some(version), data)?
+        } else {
+            bincode::deserialize(data).map_err(|e| {
+                // the layout may have changed in a newer version
+                if version > read_version(&get_version_bytes()) {
+                    Error::UnsupportedVersion(version)
+                } else {
+                    e.into()
+                }
+            })?
+        }
     } else {
         // for compatibility
-        bincode::deserialize(content)?
+        T::decode_legacy(None, content)?
     };
 
     Ok(file)
 }
 
+fn read_version(file_contents: &[u8]) -> Version {
+    let mut version = [0; VERSION_PARTS];
+    version.copy_from_slice(&file_contents[..VERSION_PARTS]);
+    version
+}
+
 pub fn get_version(file_contents: &[u8]) -> String {
     if has_signature(file_contents, &get_signature_bytes()[..]) {
         file_contents[..3]
@@ -0,0 +1,709 @@
+//! User configuration, stored as TOML in `config.toml` (see
+//! `fs::config_path`). All settings are optional:
+//!
+//! ```toml
+//! default_vault = "personal"   # vault used when `--vault` is not given
+//! vault_path = "/home/me/gk.dat"   # data file of the `default` vault
+//! output = "text"              # or "json", for commands that support it
+//! track_usage = false          # record when entries are retrieved
+//!
+//! [clipboard]
+//! backend = "x11"              # "x11", "command" or "none"
+//! command = "wl-copy"          # reads the password from stdin
+//! timeout = 30                 # seconds until it is cleared (0: ENTER only)
+//!
+//! [kdf]                        # key derivation for new vaults
+//! algorithm = "scrypt"         # or "pbkdf2"
+//! scrypt_log_n = 15
+//! scrypt_r = 8
+//! scrypt_p = 1
+//! pbkdf2_iterations = 600000
+//!
+//! [generator]
+//! length = 20
+//! symbols = true               # also `lowercase`, `uppercase` and `digits`
+//!
+//! [backup]
+//! keep = 3                     # copies of the data file kept on save
+//!
+//! [add]
+//! fields = ["description", "user", "email", "notes"]
+//!
+//! [sync]                       # see `sync`
+//! remote = "git@example.com:me/vault.git"   # or the name of a git remote
+//! branch = "main"
+//!
+//! [challenge]                  # see `challenge`
+//! command = "ykchalresp -2 -H -i-"   # reads the challenge from stdin
+//!
+//! [vaults]
+//! personal = "/home/me/.local/share/guarakapa/gk.dat"
+//! work = "/home/me/work/gk.dat"
+//! ```
+//!
+//! Each setting can be overridden by an environment variable named after it
+//! (e.g. `GUARAKAPA_CLIPBOARD_TIMEOUT` for `clipboard.timeout`), which can in
+//! turn be overridden on the command line (`-o clipboard.timeout=10`).
+
+use crate::{crypto::Kdf, fs, generator};
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Name of the vault stored in the default data file, unless a vault with
+/// the same name is registered.
+pub const DEFAULT_VAULT: &str = "default";
+
+const ENV_PREFIX: &str = "GUARAKAPA_";
+
+/// Settings that can be read and changed with `get` and `set`, besides the
+/// paths of the vaults (`vaults.NAME`)
+pub const KEYS: &[&str] = &[
+    "default_vault",
+    "vault_path",
+    "output",
+    "track_usage",
+    "clipboard.backend",
+    "clipboard.command",
+    "clipboard.timeout",
+    "kdf.algorithm",
+    "kdf.scrypt_log_n",
+    "kdf.scrypt_r",
+    "kdf.scrypt_p",
+    "kdf.pbkdf2_iterations",
+    "generator.length",
+    "generator.lowercase",
+    "generator.uppercase",
+    "generator.digits",
+    "generator.symbols",
+    "backup.keep",
+    "add.fields",
+    "sync.remote",
+    "sync.branch",
+    "challenge.command",
+];
+
+const VAULTS_SECTION: &str = "vaults";
+
+// simple values must come before the sections, as required by TOML
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Vault used when none is selected with `--vault`
+    pub default_vault: Option<String>,
+    /// Data file of the `default` vault, instead of the built-in location
+    pub vault_path: Option<PathBuf>,
+    /// Output format of the commands supporting JSON
+    pub output: OutputFormat,
+    /// Whether `get` records when an entry was used, saving the data file
+    /// (and answering its challenge, if any) on every retrieval
+    pub track_usage: bool,
+    pub clipboard: Clipboard,
+    /// Key derivation for new vaults
+    pub kdf: KdfConfig,
+    pub generator: generator::Options,
+    pub backup: Backup,
+    pub add: Add,
+    pub sync: Sync,
+    pub challenge: Challenge,
+    /// Data file of each registered vault, by name
+    pub vaults: BTreeMap<String, PathBuf>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Clipboard {
+    pub backend: ClipboardBackend,
+    /// Command receiving the password in its standard input, for the
+    /// `command` backend (e.g. `wl-copy` or `xclip -selection clipboard`)
+    pub command: Option<String>,
+    /// Seconds after which the clipboard is cleared, `0` to wait for ENTER
+    pub timeout: u64,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ClipboardBackend {
+    #[default]
+    X11,
+    Command,
+    /// Passwords are not copied anywhere
+    None,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct KdfConfig {
+    pub algorithm: KdfAlgorithm,
+    pub scrypt_log_n: u8,
+    pub scrypt_r: u32,
+    pub scrypt_p: u32,
+    pub pbkdf2_iterations: u32,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum KdfAlgorithm {
+    #[default]
+    Scrypt,
+    Pbkdf2,
+}
+
+impl Default for KdfConfig {
+    fn default() -> Self {
+        let (log_n, r, p) = match Kdf::default() {
+            Kdf::Scrypt { log_n, r, p } => (log_n, r, p),
+            _ => unreachable!(),
+        };
+
+        KdfConfig {
+            algorithm: KdfAlgorithm::Scrypt,
+            scrypt_log_n: log_n,
+            scrypt_r: r,
+            scrypt_p: p,
+            pbkdf2_iterations: 600_000,
+        }
+    }
+}
+
+impl KdfConfig {
+    /// KDF for new vaults
+    pub fn kdf(&self) -> Kdf {
+        match self.algorithm {
+            KdfAlgorithm::Scrypt => Kdf::Scrypt {
+                log_n: self.scrypt_log_n,
+                r: self.scrypt_r,
+                p: self.scrypt_p,
+            },
+            KdfAlgorithm::Pbkdf2 => Kdf::Pbkdf2 {
+                iterations: self.pbkdf2_iterations,
+            },
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        if !(10..=24).contains(&self.scrypt_log_n) {
+            bail!("`kdf.scrypt_log_n` must be between 10 and 24.");
+        }
+        if !(1..=32).contains(&self.scrypt_r) {
+            bail!("`kdf.scrypt_r` must be between 1 and 32.");
+        }
+        if !(1..=16).contains(&self.scrypt_p) {
+            bail!("`kdf.scrypt_p` must be between 1 and 16.");
+        }
+        if self.pbkdf2_iterations < 10_000 {
+            bail!("`kdf.pbkdf2_iterations` must be at least 10000.");
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Backup {
+    /// Number of previous versions of a data file kept when it is saved
+    pub keep: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Add {
+    /// Fields asked for by `add`, in order (the password is always asked)
+    pub fields: Vec<Field>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Field {
+    Description,
+    User,
+    Email,
+    Notes,
+}
+
+impl Default for Add {
+    fn default() -> Self {
+        Add {
+            fields: vec![Field::Description, Field::User, Field::Email, Field::Notes],
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Sync {
+    /// Git remote (name or URL) that data files are pulled from and pushed
+    /// to. Changes are only committed to the repository of a data file when
+    /// this is set.
+    pub remote: Option<String>,
+    pub branch: String,
+}
+
+impl Default for Sync {
+    fn default() -> Self {
+        Sync {
+            remote: None,
+            branch: String::from("main"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Challenge {
+    /// Command giving the response to the challenge of a data file, for
+    /// files that require it (e.g. with a hardware key)
+    pub command: Option<String>,
+}
+
+impl Config {
+    /// Reads the configuration file, if there is one.
+    pub fn load() -> Result<Self> {
+        Self::load_from(&fs::config_path()?)
+    }
+
+    pub fn load_from(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+
+        let contents = std::fs::read_to_string(path)?;
+        let config: Config = toml::from_str(&contents)
+            .with_context(|| format!("Invalid config file `{}`", path.display()))?;
+        config
+            .validate()
+            .with_context(|| format!("Invalid config file `{}`", path.display()))?;
+
+        Ok(config)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        self.save_to(&fs::config_path()?)
+    }
+
+    pub fn save_to(&self, path: &Path) -> Result<()> {
+        Ok(fs::save_atomically(path, self.show()?.into_bytes())?)
+    }
+
+    /// The configuration as TOML
+    pub fn show(&self) -> Result<String> {
+        Ok(toml::to_string(self)?)
+    }
+
+    /// Settings overridden by environment variables, as pairs of key and
+    /// value. `var` gives the value of a variable (e.g.
+    /// `|name| std::env::var(name).ok()`).
+    pub fn env_overrides(var: impl Fn(&str) -> Option<String>) -> Vec<(String, String)> {
+        KEYS.iter()
+            .filter_map(|key| {
+                let name = format!("{}{}", ENV_PREFIX, key.to_uppercase().replace('.', "_"));
+                var(&name).map(|value| (key.to_string(), value))
+            })
+            .collect()
+    }
+
+    /// Applies `overrides` (pairs of key and value) over this configuration.
+    pub fn with_overrides(mut self, overrides: &[(String, String)]) -> Result<Self> {
+        for (key, value) in overrides {
+            self.set(key, value)?;
+        }
+
+        Ok(self)
+    }
+
+    /// Value of the setting `key`, or `None` if it is not set.
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        let (section, name) = split_key(key)?;
+        let table = toml::Table::try_from(self)?;
+
+        let value = match section {
+            Some(section) => table
+                .get(section)
+                .and_then(|section| section.get(name))
+                .cloned(),
+            None => table.get(name).cloned(),
+        };
+
+        Ok(value.map(|value| match value {
+            toml::Value::String(s) => s,
+            value => value.to_string(),
+        }))
+    }
+
+    /// Changes the setting `key`. `value` is parsed as a TOML value (e.g.
+    /// `20`, `true` or `["user", "notes"]`), or taken as a string if it is
+    /// not one. The configuration is left unchanged if the new value is not
+    /// valid.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        let (section, name) = split_key(key)?;
+        let mut table = toml::Table::try_from(&*self)?;
+
+        let parsed = match format!("value = {}", value).parse::<toml::Table>() {
+            Ok(mut parsed) => parsed.remove("value"),
+            Err(_) => None,
+        };
+
+        // a string is also tried for values that look like other types
+        let candidates = parsed
+            .into_iter()
+            .chain(std::iter::once(toml::Value::String(value.to_string())));
+
+        let mut error = None;
+
+        for candidate in candidates {
+            let target = match section {
+                Some(section) => table
+                    .entry(section)
+                    .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+                    .as_table_mut()
+                    .ok_or_else(|| anyhow!("Invalid config section `{}`.", section))?,
+                None => &mut table,
+            };
+            target.insert(name.to_string(), candidate);
+
+            match toml::Value::Table(table.clone())
+                .try_into::<Config>()
+                .map_err(anyhow::Error::from)
+                .and_then(|config| config.validate().map(|_| config))
+            {
+                Ok(config) => {
+                    *self = config;
+                    return Ok(());
+                }
+                // the error of the parsed value is the most helpful one
+                Err(e) => {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+
+        let error = error.unwrap_or_else(|| anyhow!("unknown error"));
+        Err(error.context(format!("Invalid value `{}` for `{}`", value, key)))
+    }
+
+    /// Checks the values that are valid for their types but not accepted
+    pub fn validate(&self) -> Result<()> {
+        if let Some(name) = &self.default_vault {
+            if !self.vaults.contains_key(name) && name != DEFAULT_VAULT {
+                bail!("Default vault `{}` not found.", name);
+            }
+        }
+
+        if self.clipboard.backend == ClipboardBackend::Command
+            && self.clipboard.command.as_deref().unwrap_or("").is_empty()
+        {
+            bail!("`clipboard.command` must be set to use the `command` backend.");
+        }
+
+        self.kdf.validate()?;
+        self.generator.validate()?;
+
+        if self.backup.keep > 100 {
+            bail!("`backup.keep` must be at most 100.");
+        }
+
+        for (i, field) in self.add.fields.iter().enumerate() {
+            if self.add.fields[..i].contains(field) {
+                bail!("`add.fields` has repeated fields.");
+            }
+        }
+
+        if self.sync.branch.is_empty() || self.sync.branch.starts_with('-') {
+            bail!("Invalid `sync.branch`.");
+        }
+
+        Ok(())
+    }
+
+    /// Path of the data file of the vault `name`. If no name is given, this
+    /// is the file in `$GUARAKAPA_FILE_PATH`, the default vault or the
+    /// default data file, in this order.
+    pub fn vault_path(&self, name: Option<&str>) -> Result<PathBuf> {
+        match (name, &self.default_vault) {
+            (Some(name), _) => match self.vaults.get(name) {
+                Some(path) => Ok(path.clone()),
+                None if name == DEFAULT_VAULT => self.default_file_path(),
+                None => bail!("Vault `{}` not found.", name),
+            },
+            (None, Some(default)) if std::env::var_os(fs::PATH_ENV).is_none() => {
+                self.vault_path(Some(default))
+            }
+            (None, _) => self.default_file_path(),
+        }
+    }
+
+    /// Data file of the `default` vault: the one in `$GUARAKAPA_FILE_PATH`,
+    /// in `vault_path` or in the built-in location, in this order.
+    fn default_file_path(&self) -> Result<PathBuf> {
+        match &self.vault_path {
+            Some(path) if std::env::var_os(fs::PATH_ENV).is_none() => Ok(path.clone()),
+            _ => fs::default_file_path(),
+        }
+    }
+
+    /// Name of the vault used when none is selected
+    pub fn default_vault_name(&self) -> &str {
+        self.default_vault.as_deref().unwrap_or(DEFAULT_VAULT)
+    }
+
+    /// All vaults, including the one in the default data file, with the
+    /// paths of their data files.
+    pub fn vaults(&self) -> Result<Vec<(String, PathBuf)>> {
+        let mut vaults = self.vaults.clone();
+        if !vaults.contains_key(DEFAULT_VAULT) {
+            vaults.insert(DEFAULT_VAULT.to_string(), self.default_file_path()?);
+        }
+        Ok(vaults.into_iter().collect())
+    }
+
+    /// Registers the vault `name`, stored in the data file at `path`.
+    pub fn add_vault(&mut self, name: &str, path: &Path) -> Result<()> {
+        if name.is_empty() || name.starts_with('-') {
+            bail!("Invalid vault name `{}`.", name);
+        }
+        if self.vaults.contains_key(name) {
+            bail!("Vault `{}` already exists.", name);
+        }
+
+        self.vaults
+            .insert(name.to_string(), std::path::absolute(path)?);
+        Ok(())
+    }
+
+    /// Unregisters the vault `name`. Its data file is kept.
+    pub fn remove_vault(&mut self, name: &str) -> Result<()> {
+        if self.vaults.remove(name).is_none() {
+            bail!("Vault `{}` not found.", name);
+        }
+        if self.default_vault.as_deref() == Some(name) {
+            self.default_vault = None;
+        }
+
+        Ok(())
+    }
+
+    pub fn set_default_vault(&mut self, name: &str) -> Result<()> {
+        if !self.vaults.contains_key(name) && name != DEFAULT_VAULT {
+            bail!("Vault `{}` not found.", name);
+        }
+
+        self.default_vault = Some(name.to_string());
+        Ok(())
+    }
+}
+
+/// Splits a key into its section (if any) and name, checking that it is a
+/// known setting.
+fn split_key(key: &str) -> Result<(Option<&str>, &str)> {
+    if let Some(name) = key
+        .strip_prefix(VAULTS_SECTION)
+        .and_then(|k| k.strip_prefix('.'))
+    {
+        if !name.is_empty() {
+            return Ok((Some(VAULTS_SECTION), name));
+        }
+    }
+
+    if !KEYS.contains(&key) {
+        bail!("Unknown setting `{}`.", key);
+    }
+
+    Ok(match key.split_once('.') {
+        Some((section, name)) => (Some(section), name),
+        None => (None, key),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        toml::from_str(
+            r#"
+            default_vault = "work"
+
+            [vaults]
+            work = "/tmp/work.dat"
+            home = "/tmp/home.dat"
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn finds_vault_paths() {
+        let config = config();
+
+        assert_eq!(
+            config.vault_path(Some("home")).unwrap(),
+            Path::new("/tmp/home.dat")
+        );
+        assert_eq!(
+            config.vault_path(Some(DEFAULT_VAULT)).unwrap(),
+            fs::default_file_path().unwrap()
+        );
+        assert!(config.vault_path(Some("other")).is_err());
+
+        if std::env::var_os(fs::PATH_ENV).is_none() {
+            assert_eq!(config.vault_path(None).unwrap(), Path::new("/tmp/work.dat"));
+
+            let config = Config {
+                vault_path: Some(PathBuf::from("/tmp/main.dat")),
+                ..Default::default()
+            };
+            assert_eq!(config.vault_path(None).unwrap(), Path::new("/tmp/main.dat"));
+        }
+        assert_eq!(
+            Config::default().vault_path(None).unwrap(),
+            fs::default_file_path().unwrap()
+        );
+    }
+
+    #[test]
+    fn lists_default_vault() {
+        let names: Vec<String> = config()
+            .vaults()
+            .unwrap()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(names, vec!["default", "home", "work"]);
+    }
+
+    #[test]
+    fn can_register_vaults() {
+        let mut config = config();
+
+        assert!(config.add_vault("work", Path::new("/tmp/x.dat")).is_err());
+        assert!(config
+            .add_vault("--vault", Path::new("/tmp/x.dat"))
+            .is_err());
+        config.add_vault("new", Path::new("/tmp/new.dat")).unwrap();
+        config.set_default_vault("new").unwrap();
+        assert!(config.set_default_vault("missing").is_err());
+
+        config.remove_vault("new").unwrap();
+        assert_eq!(config.default_vault, None);
+        assert!(config.remove_vault("new").is_err());
+    }
+
+    #[test]
+    fn can_get_and_set_values() {
+        let mut config = config();
+
+        assert_eq!(config.get("clipboard.timeout").unwrap().unwrap(), "0");
+        assert_eq!(config.get("clipboard.command").unwrap(), None);
+        assert_eq!(config.get("default_vault").unwrap().unwrap(), "work");
+        assert_eq!(config.get("vaults.home").unwrap().unwrap(), "/tmp/home.dat");
+
+        config.set("clipboard.timeout", "30").unwrap();
+        config.set("output", "json").unwrap();
+        config.set("generator.symbols", "false").unwrap();
+        config.set("add.fields", r#"["user", "notes"]"#).unwrap();
+        config.set("vaults.other", "/tmp/other.dat").unwrap();
+        // a string that is also a valid number
+        config.set("default_vault", "123").unwrap_err();
+        config.set("vaults.123", "/tmp/123.dat").unwrap();
+        config.set("default_vault", "123").unwrap();
+
+        assert_eq!(config.clipboard.timeout, 30);
+        assert_eq!(config.output, OutputFormat::Json);
+        assert!(!config.generator.symbols);
+        assert_eq!(config.add.fields, vec![Field::User, Field::Notes]);
+        assert_eq!(config.vaults["other"], Path::new("/tmp/other.dat"));
+        assert_eq!(config.default_vault.as_deref(), Some("123"));
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        let mut config = config();
+        let original = config.clone();
+
+        for (key, value) in [
+            ("clipboard.timeout", "-1"),
+            ("clipboard.backend", "wayland"),
+            ("clipboard.backend", "command"),
+            ("kdf.algorithm", "md5"),
+            ("kdf.scrypt_log_n", "4"),
+            ("kdf.pbkdf2_iterations", "1"),
+            ("generator.length", "2"),
+            ("generator.symbols", "maybe"),
+            ("add.fields", r#"["user", "user"]"#),
+            ("add.fields", r#"["pin"]"#),
+            ("default_vault", "missing"),
+            ("unknown", "1"),
+            ("kdf.unknown", "1"),
+            ("vaults.", "/tmp/x.dat"),
+        ] {
+            assert!(config.set(key, value).is_err(), "{} = {}", key, value);
+        }
+
+        assert_eq!(config, original);
+        assert!(config.get("unknown").is_err());
+    }
+
+    #[test]
+    fn overrides_apply_in_order() {
+        let config = Config::default()
+            .with_overrides(&[
+                ("backup.keep".to_string(), "2".to_string()),
+                ("backup.keep".to_string(), "5".to_string()),
+            ])
+            .unwrap();
+        assert_eq!(config.backup.keep, 5);
+
+        let env = BTreeMap::from([
+            ("GUARAKAPA_GENERATOR_LENGTH", "32"),
+            ("GUARAKAPA_UNKNOWN", "1"),
+        ]);
+        let overrides = Config::env_overrides(|name| env.get(name).map(|v| v.to_string()));
+
+        assert_eq!(
+            overrides,
+            vec![("generator.length".to_string(), "32".to_string())]
+        );
+    }
+
+    #[test]
+    fn new_vaults_use_configured_kdf() {
+        let mut config = Config::default();
+        assert_eq!(config.kdf.kdf(), Kdf::default());
+
+        config.set("kdf.algorithm", "pbkdf2").unwrap();
+        config.set("kdf.pbkdf2_iterations", "100000").unwrap();
+        assert_eq!(
+            config.kdf.kdf(),
+            Kdf::Pbkdf2 {
+                iterations: 100_000
+            }
+        );
+    }
+
+    #[test]
+    fn can_save_and_load() {
+        let path = std::env::temp_dir().join(format!("gk-config-{}.toml", std::process::id()));
+        let mut config = config();
+        config.set("clipboard.backend", "none").unwrap();
+        config.set("kdf.scrypt_log_n", "12").unwrap();
+
+        config.save_to(&path).unwrap();
+        assert_eq!(Config::load_from(&path).unwrap(), config);
+
+        std::fs::write(&path, "[clipboard]\ntimeout = \"never\"\n").unwrap();
+        assert!(Config::load_from(&path).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(Config::load_from(&path).unwrap(), Config::default());
+    }
+}
@@ -1,5 +1,24 @@
-use anyhow::Result;
+use crate::error::{Error, Result};
+use openssl::{
+    derive::Deriver,
+    encrypt::{Decrypter, Encrypter},
+    pkey::{HasPrivate, HasPublic, Id, PKey, PKeyRef, Private},
+    rsa::{Padding, Rsa},
+    sign::Signer,
+    symm::{Cipher, Crypter, Mode},
+};
 use rand::prelude::*;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+use zeroize::Zeroize;
+
+const KEY_LEN: usize = 32;
+const IV_LEN: usize = 16;
+const X25519_KEY_LEN: usize = 32;
+
+/// Size of the RSA keys generated for team vaults
+const RSA_BITS: u32 = 3072;
 
 /// Encrypt a message using a key and an initialization vector
 pub fn encrypt(content: &[u8], iv: &[u8], key: &[u8]) -> Result<Vec<u8>> {
@@ -10,15 +29,95 @@ pub fn encrypt(content: &[u8], iv: &[u8], key: &[u8]) -> Result<Vec<u8>> {
 
 /// Decrypt a message using the key and initialization vector that were
 /// used to encrypt it.
-pub fn decrypt(secret: &[u8], iv: &[u8], key: &[u8]) -> Result<Vec<u8>> {
+pub fn decrypt(secret: &[u8], iv: &[u8], key: &[u8]) -> Result<SecretBytes> {
     let decrypted =
         openssl::symm::decrypt(openssl::symm::Cipher::aes_256_cbc(), key, Some(iv), secret)?;
-    Ok(decrypted)
+    Ok(decrypted.into())
+}
+
+/// Encrypts `content`, whose length must be a multiple of the block size (16
+/// bytes), without padding: any prefix of the result can be decrypted alone
+/// with `decrypt_blocks`, and bytes can be appended to it.
+pub fn encrypt_blocks(content: &[u8], iv: &[u8], key: &[u8]) -> Result<Vec<u8>> {
+    crypt_blocks(Mode::Encrypt, content, iv, key)
+}
+
+/// Decrypts blocks encrypted by `encrypt_blocks` (or a prefix of them).
+pub fn decrypt_blocks(secret: &[u8], iv: &[u8], key: &[u8]) -> Result<SecretBytes> {
+    Ok(crypt_blocks(Mode::Decrypt, secret, iv, key)?.into())
+}
+
+fn crypt_blocks(mode: Mode, input: &[u8], iv: &[u8], key: &[u8]) -> Result<Vec<u8>> {
+    let cipher = Cipher::aes_256_cbc();
+    let mut crypter = Crypter::new(cipher, mode, key, Some(iv))?;
+    crypter.pad(false);
+
+    let mut output = vec![0; input.len() + cipher.block_size()];
+    let mut len = crypter.update(input, &mut output)?;
+    len += crypter.finalize(&mut output[len..])?;
+    output.truncate(len);
+    Ok(output)
+}
+
+/// Derives a 256-bit key from a password (or other secret input) and a salt
+/// value.
+pub fn derive_key(password: impl AsRef<[u8]>, salt: &[u8]) -> SecretBytes {
+    let mut key = hash(vec![password.as_ref(), salt]);
+    let secret = SecretBytes::from(&key[..]);
+    key.zeroize();
+    secret
 }
 
-/// Derives a 256-bit key from a password string and a salt value.
-pub fn derive_key(password: String, salt: &[u8]) -> [u8; 32] {
-    hash(vec![password.as_bytes(), salt])
+/// Function deriving the encryption key from the master password, with its
+/// cost parameters.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub enum Kdf {
+    /// A single SHA-256 of the password and salt (see `derive_key`), used by
+    /// files created before the key derivation could be configured
+    Sha256,
+    /// PBKDF2 with HMAC-SHA256
+    Pbkdf2 { iterations: u32 },
+    /// scrypt, with cost `N = 2^log_n`, block size `r` and parallelism `p`
+    Scrypt { log_n: u8, r: u32, p: u32 },
+}
+
+impl Default for Kdf {
+    fn default() -> Self {
+        Kdf::Scrypt {
+            log_n: 15,
+            r: 8,
+            p: 1,
+        }
+    }
+}
+
+impl Kdf {
+    /// Derives a 256-bit key from a password (or other secret input) and a
+    /// salt value.
+    pub fn derive_key(&self, password: impl AsRef<[u8]>, salt: &[u8]) -> Result<SecretBytes> {
+        let password = password.as_ref();
+        let mut key = SecretBytes::zeroed(KEY_LEN);
+
+        match *self {
+            Kdf::Sha256 => return Ok(derive_key(password, salt)),
+            Kdf::Pbkdf2 { iterations } => openssl::pkcs5::pbkdf2_hmac(
+                password,
+                salt,
+                iterations as usize,
+                openssl::hash::MessageDigest::sha256(),
+                &mut key,
+            )?,
+            Kdf::Scrypt { log_n, r, p } => {
+                let n = 1u64.checked_shl(log_n.into()).unwrap_or(0);
+                // memory used by scrypt, with some room for OpenSSL
+                let max_mem = 128 * u64::from(r) * (n + u64::from(p) + 2) + (1 << 20);
+
+                openssl::pkcs5::scrypt(password, salt, n, r.into(), p.into(), max_mem, &mut key)?
+            }
+        }
+
+        Ok(key)
+    }
 }
 
 pub fn hash(content: Vec<&[u8]>) -> [u8; 32] {
@@ -30,6 +129,155 @@ pub fn hash(content: Vec<&[u8]>) -> [u8; 32] {
     hasher.finish()
 }
 
+/// HMAC-SHA256 of `content` with `key`
+pub fn hmac(key: &[u8], content: &[u8]) -> Result<[u8; 32]> {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(openssl::hash::MessageDigest::sha256(), &key)?;
+    signer.update(content)?;
+
+    let mut mac = [0; 32];
+    signer.sign(&mut mac)?;
+    Ok(mac)
+}
+
+/// Generates a random 256-bit key
+pub fn random_key() -> SecretBytes {
+    generate_bytes(KEY_LEN).into()
+}
+
+/// Private key of a user, opening the vault key of the team vaults they are
+/// a member of (see `fman::Member`). It is an X25519 or RSA key, kept as
+/// PKCS#8 DER.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Keypair(SecretBytes);
+
+impl Keypair {
+    pub fn generate_x25519() -> Result<Self> {
+        Self::from_pkey(&PKey::generate_x25519()?)
+    }
+
+    pub fn generate_rsa() -> Result<Self> {
+        Self::from_pkey(&PKey::from_rsa(Rsa::generate(RSA_BITS)?)?)
+    }
+
+    /// X25519 keypair whose private key is `secret` (32 bytes), e.g. derived
+    /// from a recovery key
+    pub fn x25519_from_secret(secret: &[u8]) -> Result<Self> {
+        Self::from_pkey(&PKey::private_key_from_raw_bytes(secret, Id::X25519)?)
+    }
+
+    fn from_pkey(pkey: &PKey<Private>) -> Result<Self> {
+        Ok(Keypair(pkey.private_key_to_pkcs8()?.into()))
+    }
+
+    fn pkey(&self) -> Result<PKey<Private>> {
+        Ok(PKey::private_key_from_pkcs8(&self.0)?)
+    }
+
+    /// Public key, as DER
+    pub fn public_key(&self) -> Result<Vec<u8>> {
+        Ok(self.pkey()?.public_key_to_der()?)
+    }
+}
+
+/// Reads a public key in PEM format, returning it as DER. Only X25519 and
+/// RSA keys are accepted.
+pub fn public_key_from_pem(pem: &[u8]) -> Result<Vec<u8>> {
+    match PKey::public_key_from_pem(pem) {
+        Ok(pkey) if [Id::X25519, Id::RSA].contains(&pkey.id()) => Ok(pkey.public_key_to_der()?),
+        _ => Err(Error::InvalidKey),
+    }
+}
+
+pub fn public_key_to_pem(der: &[u8]) -> Result<String> {
+    let pem = PKey::public_key_from_der(der)?.public_key_to_pem()?;
+    Ok(String::from_utf8_lossy(&pem).into_owned())
+}
+
+/// Encrypts `key` so that only the owner of the private key matching
+/// `public_key` (DER) can decrypt it. RSA keys encrypt it directly (with
+/// OAEP padding); for X25519 keys, it is encrypted with a key agreed with an
+/// ephemeral key, whose public part is stored before the IV and the
+/// encrypted key.
+pub fn wrap_key(public_key: &[u8], key: &[u8]) -> Result<Vec<u8>> {
+    let recipient = PKey::public_key_from_der(public_key).map_err(|_| Error::InvalidKey)?;
+
+    match recipient.id() {
+        Id::X25519 => {
+            let ephemeral = PKey::generate_x25519()?;
+            let mut wrapped = ephemeral.raw_public_key()?;
+            let agreed = agreed_key(
+                &ephemeral,
+                &recipient,
+                &wrapped,
+                &recipient.raw_public_key()?,
+            )?;
+
+            let iv = generate_bytes(IV_LEN);
+            wrapped.extend_from_slice(&iv);
+            wrapped.append(&mut encrypt(key, &iv, &agreed)?);
+            Ok(wrapped)
+        }
+        Id::RSA => {
+            let mut encrypter = Encrypter::new(&recipient)?;
+            encrypter.set_rsa_padding(Padding::PKCS1_OAEP)?;
+
+            let mut wrapped = vec![0; encrypter.encrypt_len(key)?];
+            let len = encrypter.encrypt(key, &mut wrapped)?;
+            wrapped.truncate(len);
+            Ok(wrapped)
+        }
+        _ => Err(Error::InvalidKey),
+    }
+}
+
+/// Decrypts a key encrypted by `wrap_key` for the public key of `keypair`.
+pub fn unwrap_key(keypair: &Keypair, wrapped: &[u8]) -> Result<SecretBytes> {
+    let pkey = keypair.pkey()?;
+
+    match pkey.id() {
+        Id::X25519 => {
+            if wrapped.len() < X25519_KEY_LEN + IV_LEN {
+                return Err(Error::corrupt("wrapped key too short", "truncated data"));
+            }
+
+            let (ephemeral, rest) = wrapped.split_at(X25519_KEY_LEN);
+            let (iv, content) = rest.split_at(IV_LEN);
+            let ephemeral_key = PKey::public_key_from_raw_bytes(ephemeral, Id::X25519)?;
+            let agreed = agreed_key(&pkey, &ephemeral_key, ephemeral, &pkey.raw_public_key()?)?;
+
+            decrypt(content, iv, &agreed)
+        }
+        Id::RSA => {
+            let mut decrypter = Decrypter::new(&pkey)?;
+            decrypter.set_rsa_padding(Padding::PKCS1_OAEP)?;
+
+            let mut key = SecretBytes::zeroed(decrypter.decrypt_len(wrapped)?);
+            let len = decrypter.decrypt(wrapped, &mut key)?;
+            Ok(SecretBytes::from(&key[..len]))
+        }
+        _ => Err(Error::InvalidKey),
+    }
+}
+
+/// Key agreed between `private` and `public` with X25519, bound to the
+/// public keys of the ephemeral key and of the recipient.
+fn agreed_key<T: HasPrivate, U: HasPublic>(
+    private: &PKeyRef<T>,
+    public: &PKeyRef<U>,
+    ephemeral_public: &[u8],
+    recipient_public: &[u8],
+) -> Result<SecretBytes> {
+    let mut deriver = Deriver::new(private)?;
+    deriver.set_peer(public)?;
+    let shared = SecretBytes::from(deriver.derive_to_vec()?);
+
+    let mut key = hash(vec![&shared, ephemeral_public, recipient_public]);
+    let secret = SecretBytes::from(&key[..]);
+    key.zeroize();
+    Ok(secret)
+}
+
 /// Generates a random sequence of bytes
 pub fn generate_bytes(n: usize) -> Vec<u8> {
     let mut bytes = vec![0; n];
@@ -39,6 +287,237 @@ pub fn generate_bytes(n: usize) -> Vec<u8> {
     bytes
 }
 
+/// Core dumps are disabled while at least one secret buffer is alive. This
+/// keeps the state needed to restore the previous settings afterwards.
+struct DumpProtection {
+    holders: usize,
+    core_limit: Option<libc::rlimit>,
+    dumpable: Option<libc::c_int>,
+}
+
+static DUMP_PROTECTION: Mutex<DumpProtection> = Mutex::new(DumpProtection {
+    holders: 0,
+    core_limit: None,
+    dumpable: None,
+});
+
+fn acquire_dump_protection() {
+    let mut protection = DUMP_PROTECTION.lock().unwrap_or_else(|e| e.into_inner());
+
+    if protection.holders == 0 {
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+
+        // SAFETY: plain system calls on a valid `rlimit` struct
+        unsafe {
+            if libc::getrlimit(libc::RLIMIT_CORE, &mut limit) == 0 {
+                protection.core_limit = Some(limit);
+                let no_core = libc::rlimit {
+                    rlim_cur: 0,
+                    rlim_max: limit.rlim_max,
+                };
+                libc::setrlimit(libc::RLIMIT_CORE, &no_core);
+            }
+
+            #[cfg(target_os = "linux")]
+            {
+                let dumpable = libc::prctl(libc::PR_GET_DUMPABLE);
+                if dumpable >= 0 {
+                    protection.dumpable = Some(dumpable);
+                    libc::prctl(libc::PR_SET_DUMPABLE, 0);
+                }
+            }
+        }
+    }
+
+    protection.holders += 1;
+}
+
+fn release_dump_protection() {
+    let mut protection = DUMP_PROTECTION.lock().unwrap_or_else(|e| e.into_inner());
+    protection.holders -= 1;
+
+    if protection.holders == 0 {
+        // SAFETY: plain system calls restoring the values read before
+        unsafe {
+            if let Some(limit) = protection.core_limit.take() {
+                libc::setrlimit(libc::RLIMIT_CORE, &limit);
+            }
+
+            #[cfg(target_os = "linux")]
+            if let Some(dumpable) = protection.dumpable.take() {
+                libc::prctl(libc::PR_SET_DUMPABLE, dumpable as libc::c_ulong);
+            }
+        }
+    }
+}
+
+/// Whether core dumps are currently disabled to protect secrets
+pub fn core_dumps_disabled() -> bool {
+    DUMP_PROTECTION
+        .lock()
+        .map(|protection| protection.holders > 0)
+        .unwrap_or(true)
+}
+
+/// Heap buffer for secret data (keys, passwords, decrypted content). Its
+/// pages are locked in memory, so that they are not written to swap, and it
+/// is wiped when dropped. Core dumps are disabled while it is alive.
+pub struct SecretBytes {
+    bytes: Box<[u8]>,
+    locked: bool,
+}
+
+impl SecretBytes {
+    /// Allocates a secret buffer of `len` zeroes
+    pub fn zeroed(len: usize) -> Self {
+        acquire_dump_protection();
+        let bytes = vec![0; len].into_boxed_slice();
+
+        // locking can fail if the process exceeds its limit of locked
+        // memory, in which case the buffer is still wiped on drop
+        // SAFETY: the range is the allocation owned by `bytes`
+        let locked = !bytes.is_empty()
+            && unsafe { libc::mlock(bytes.as_ptr() as *const libc::c_void, bytes.len()) } == 0;
+
+        SecretBytes { bytes, locked }
+    }
+
+    /// Wipes the contents of the buffer
+    fn wipe(&mut self) {
+        self.bytes.zeroize();
+    }
+}
+
+impl Drop for SecretBytes {
+    fn drop(&mut self) {
+        self.wipe();
+
+        if self.locked {
+            // SAFETY: the range was locked in `zeroed` and is still allocated
+            unsafe {
+                libc::munlock(self.bytes.as_ptr() as *const libc::c_void, self.bytes.len());
+            }
+        }
+
+        release_dump_protection();
+    }
+}
+
+impl Deref for SecretBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl DerefMut for SecretBytes {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.bytes
+    }
+}
+
+impl From<&[u8]> for SecretBytes {
+    fn from(bytes: &[u8]) -> Self {
+        let mut secret = SecretBytes::zeroed(bytes.len());
+        secret.copy_from_slice(bytes);
+        secret
+    }
+}
+
+/// Moves the contents of a vector into a secret buffer, wiping the vector
+/// (including any spare capacity).
+impl From<Vec<u8>> for SecretBytes {
+    fn from(mut bytes: Vec<u8>) -> Self {
+        let secret = SecretBytes::from(&bytes[..]);
+        bytes.zeroize();
+        secret
+    }
+}
+
+impl Clone for SecretBytes {
+    fn clone(&self) -> Self {
+        SecretBytes::from(&self[..])
+    }
+}
+
+impl PartialEq for SecretBytes {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && openssl::memcmp::eq(self, other)
+    }
+}
+
+impl std::fmt::Debug for SecretBytes {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "SecretBytes(***)")
+    }
+}
+
+impl Serialize for SecretBytes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for SecretBytes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        Ok(Vec::<u8>::deserialize(deserializer)?.into())
+    }
+}
+
+/// A string (e.g. a password) stored in a `SecretBytes` buffer
+#[derive(Clone, PartialEq)]
+pub struct SecretString(SecretBytes);
+
+impl Deref for SecretString {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        // SAFETY: the buffer is only ever built from valid UTF-8
+        unsafe { std::str::from_utf8_unchecked(&self.0) }
+    }
+}
+
+impl From<&str> for SecretString {
+    fn from(s: &str) -> Self {
+        SecretString(SecretBytes::from(s.as_bytes()))
+    }
+}
+
+/// Moves the contents of a string into a secret buffer, wiping the string.
+impl From<String> for SecretString {
+    fn from(s: String) -> Self {
+        SecretString(SecretBytes::from(s.into_bytes()))
+    }
+}
+
+impl Default for SecretString {
+    fn default() -> Self {
+        SecretString::from("")
+    }
+}
+
+impl std::fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "SecretString(***)")
+    }
+}
+
+impl Serialize for SecretString {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for SecretString {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        Ok(String::deserialize(deserializer)?.into())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -47,25 +526,92 @@ mod tests {
     fn encrypting_and_decrypting_should_retrieve_content() {
         let content = "This is my text.\n\nLet's see if I can retrieve it!.";
         let salt = generate_bytes(16);
-        let pw = derive_key("very strong secret!".to_string(), &salt[..]);
+        let pw = derive_key("very strong secret!", &salt[..]);
         let iv = generate_bytes(16);
 
         let encrypted = encrypt(content.as_bytes(), &iv[..], &pw[..]).unwrap();
         let decrypted = decrypt(encrypted.as_slice(), &iv[..], &pw[..]).unwrap();
-        assert_eq!(content.as_bytes(), decrypted.as_slice());
+        assert_eq!(content.as_bytes(), &decrypted[..]);
     }
 
     #[test]
     fn encrypting_should_yield_something_different() {
         let content = "This is my text.\n\nLet's see if I can retrieve it!.";
         let salt = generate_bytes(16);
-        let pw = derive_key("very strong secret!".to_string(), &salt[..]);
+        let pw = derive_key("very strong secret!", &salt[..]);
         let iv = generate_bytes(16);
 
         let encrypted = encrypt(content.as_bytes(), &iv[..], &pw[..]).unwrap();
         assert!(content.as_bytes() != encrypted.as_slice());
     }
 
+    #[test]
+    fn secret_buffers_are_wiped() {
+        let mut secret = SecretBytes::from(&b"my secret"[..]);
+        secret.wipe();
+        assert!(secret.iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn core_dumps_are_disabled_while_secrets_are_alive() {
+        let secret = SecretString::from("my secret");
+        assert!(core_dumps_disabled());
+        assert_eq!(&*secret, "my secret");
+    }
+
+    #[test]
+    fn key_derivation_functions_match_known_values() {
+        let hex = |key: SecretBytes| -> String {
+            key.iter().map(|byte| format!("{:02x}", byte)).collect()
+        };
+
+        // RFC 7914, section 12
+        let scrypt = Kdf::Scrypt {
+            log_n: 4,
+            r: 1,
+            p: 1,
+        };
+        assert_eq!(
+            hex(scrypt.derive_key("", b"").unwrap()),
+            "77d6576238657b203b19ca42c18a0497f16b4844e3074ae8dfdffa3fede21442"
+        );
+
+        // RFC 7914, section 11
+        let pbkdf2 = Kdf::Pbkdf2 { iterations: 1 };
+        assert_eq!(
+            hex(pbkdf2.derive_key("passwd", b"salt").unwrap()),
+            "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc"
+        );
+
+        assert_eq!(
+            Kdf::Sha256.derive_key("pw", b"salt").unwrap(),
+            derive_key("pw", b"salt")
+        );
+    }
+
+    #[test]
+    fn wrapped_keys_are_opened_by_their_keypair() {
+        let key = random_key();
+
+        for keypair in [Keypair::generate_x25519(), Keypair::generate_rsa()] {
+            let keypair = keypair.unwrap();
+            let public_key = keypair.public_key().unwrap();
+            let pem = public_key_to_pem(&public_key).unwrap();
+            assert_eq!(public_key_from_pem(pem.as_bytes()).unwrap(), public_key);
+
+            let wrapped = wrap_key(&public_key, &key).unwrap();
+            assert_eq!(unwrap_key(&keypair, &wrapped).unwrap(), key);
+
+            let other = Keypair::generate_x25519().unwrap();
+            assert!(unwrap_key(&other, &wrapped).map_or(true, |k| k != key));
+        }
+
+        assert!(matches!(
+            public_key_from_pem(b"not a key"),
+            Err(Error::InvalidKey)
+        ));
+    }
+
     #[test]
     fn generated_bytes_are_different() {
         let bytes1 = generate_bytes(1024);
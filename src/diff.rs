@@ -0,0 +1,244 @@
+//! Differences between the decrypted entries of two data files (e.g. a data
+//! file and one of its backups).
+//!
+//! Entries are matched by name. An entry removed from one file and added to
+//! the other with the same contents is reported as renamed. Only the contents
+//! of entries are compared: timestamps alone are never a modification.
+
+use crate::fman::OpenEntry;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Difference between the two files
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Change {
+    /// The entry is only in the second file
+    Added { name: String },
+    /// The entry is only in the first file
+    Removed { name: String },
+    /// The entry has another name in the second file
+    Renamed { from: String, to: String },
+    /// The entry has different contents in the second file
+    Modified {
+        name: String,
+        fields: Vec<FieldChange>,
+    },
+}
+
+/// Field of an entry with different values in the two files. Values of
+/// secret fields (the password) are `None` unless secrets are shown.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct FieldChange {
+    pub field: &'static str,
+    pub secret: bool,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+/// Compares `old` and `new` (pairs of entry name and contents). Changes are
+/// in alphabetical order of entry name (the old one, for renames).
+pub fn diff(
+    old: &[(String, OpenEntry)],
+    new: &[(String, OpenEntry)],
+    show_secrets: bool,
+) -> Vec<Change> {
+    let old: BTreeMap<&str, &OpenEntry> = old.iter().map(|(n, e)| (n.as_str(), e)).collect();
+    let new: BTreeMap<&str, &OpenEntry> = new.iter().map(|(n, e)| (n.as_str(), e)).collect();
+
+    let mut added: Vec<&str> = new
+        .keys()
+        .filter(|name| !old.contains_key(*name))
+        .copied()
+        .collect();
+    let mut changes = Vec::new();
+
+    for (name, old_entry) in &old {
+        let change = match new.get(name) {
+            Some(new_entry) => {
+                let fields = field_changes(old_entry, new_entry, show_secrets);
+                if fields.is_empty() {
+                    continue;
+                }
+                Change::Modified {
+                    name: name.to_string(),
+                    fields,
+                }
+            }
+            None => match added
+                .iter()
+                .position(|added| field_changes(old_entry, new[added], true).is_empty())
+            {
+                Some(i) => Change::Renamed {
+                    from: name.to_string(),
+                    to: added.remove(i).to_string(),
+                },
+                None => Change::Removed {
+                    name: name.to_string(),
+                },
+            },
+        };
+
+        changes.push(change);
+    }
+
+    let mut added: Vec<Change> = added
+        .into_iter()
+        .map(|name| Change::Added {
+            name: name.to_string(),
+        })
+        .collect();
+    changes.append(&mut added);
+    changes.sort_by(|a, b| a.name().cmp(b.name()));
+
+    changes
+}
+
+impl Change {
+    /// Name of the entry (in the first file, for renames)
+    pub fn name(&self) -> &str {
+        match self {
+            Change::Added { name } | Change::Removed { name } | Change::Modified { name, .. } => {
+                name
+            }
+            Change::Renamed { from, .. } => from,
+        }
+    }
+}
+
+fn field_changes(old: &OpenEntry, new: &OpenEntry, show_secrets: bool) -> Vec<FieldChange> {
+    let fields = [
+        ("description", &old.desc, &new.desc, false),
+        ("user", &old.user, &new.user, false),
+        ("email", &old.email, &new.email, false),
+        ("notes", &old.notes, &new.notes, false),
+    ];
+
+    let mut changes: Vec<FieldChange> = fields
+        .into_iter()
+        .filter(|(_, old, new, _)| old != new)
+        .map(|(field, old, new, secret)| FieldChange {
+            field,
+            secret,
+            old: Some(old.clone()),
+            new: Some(new.clone()),
+        })
+        .collect();
+
+    if old.pw != new.pw {
+        let reveal = |pw: &str| show_secrets.then(|| pw.to_string());
+        changes.push(FieldChange {
+            field: "password",
+            secret: true,
+            old: reveal(&old.pw),
+            new: reveal(&new.pw),
+        });
+    }
+
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(user: &str, pw: &str) -> OpenEntry {
+        OpenEntry {
+            user: user.to_string(),
+            pw: pw.into(),
+            ..Default::default()
+        }
+    }
+
+    fn entries(entries: &[(&str, OpenEntry)]) -> Vec<(String, OpenEntry)> {
+        entries
+            .iter()
+            .map(|(name, entry)| (name.to_string(), entry.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn reports_all_kinds_of_changes() {
+        let old = entries(&[
+            ("bank", entry("me", "1")),
+            ("mail", entry("me", "2")),
+            ("old", entry("me", "3")),
+            ("same", entry("me", "4")),
+        ]);
+        let new = entries(&[
+            ("bank", entry("you", "1")),
+            ("mail", entry("me", "changed")),
+            ("new", entry("me", "3")),
+            ("other", entry("me", "5")),
+            ("same", entry("me", "4")),
+        ]);
+
+        let changes = diff(&old, &new, false);
+
+        assert_eq!(
+            changes,
+            vec![
+                Change::Modified {
+                    name: String::from("bank"),
+                    fields: vec![FieldChange {
+                        field: "user",
+                        secret: false,
+                        old: Some(String::from("me")),
+                        new: Some(String::from("you")),
+                    }],
+                },
+                Change::Modified {
+                    name: String::from("mail"),
+                    fields: vec![FieldChange {
+                        field: "password",
+                        secret: true,
+                        old: None,
+                        new: None,
+                    }],
+                },
+                Change::Renamed {
+                    from: String::from("old"),
+                    to: String::from("new"),
+                },
+                Change::Added {
+                    name: String::from("other"),
+                },
+            ]
+        );
+
+        let changes = diff(&new, &old, true);
+        assert_eq!(
+            changes[1],
+            Change::Modified {
+                name: String::from("mail"),
+                fields: vec![FieldChange {
+                    field: "password",
+                    secret: true,
+                    old: Some(String::from("changed")),
+                    new: Some(String::from("2")),
+                }],
+            }
+        );
+        assert_eq!(
+            changes[3],
+            Change::Removed {
+                name: String::from("other"),
+            }
+        );
+    }
+
+    #[test]
+    fn timestamps_are_not_compared() {
+        let old = entries(&[("a", entry("me", "1"))]);
+        let new = entries(&[(
+            "a",
+            OpenEntry {
+                modified: 10,
+                last_used: Some(20),
+                ..entry("me", "1")
+            },
+        )]);
+
+        assert!(diff(&old, &new, false).is_empty());
+    }
+}
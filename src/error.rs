@@ -0,0 +1,283 @@
+//! Errors of the operations on data files (`fman`, `codec` and `crypto`) and
+//! on their recovery keys (`recovery` and `escrow`), so that callers can tell
+//! a wrong password from a damaged file or a missing entry.
+
+use crate::codec::Version;
+use std::fmt;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+type Source = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// The master password (or key) does not match the data file
+    WrongPassword,
+    /// There is already an entry with this name
+    EntryExists(String),
+    /// There is no entry with this name
+    EntryNotFound(String),
+    /// The vault was opened read-only, and cannot be changed
+    ReadOnly,
+    /// The data file can only be unlocked with a key file, which was not
+    /// given
+    KeyFileRequired,
+    /// The data file can only be unlocked with the response to its
+    /// challenge, which was not given
+    ResponseRequired,
+    /// The response to the challenge of the data file does not match
+    WrongResponse,
+    /// The vault was unlocked with its key, but the operation also needs the
+    /// master password
+    PasswordRequired,
+    /// The recovery key is mistyped (its checksum does not match)
+    InvalidRecoveryKey,
+    /// The recovery key does not open any key slot of the data file (e.g.
+    /// because its slot was revoked)
+    WrongRecoveryKey,
+    /// There is no recovery key slot with this id
+    SlotNotFound(String),
+    /// The share of a recovery key is mistyped (see `escrow`)
+    InvalidShare,
+    /// The number of shares needed to rebuild a secret is not between 2 and
+    /// the number of shares
+    InvalidThreshold,
+    /// Only secrets a multiple of 5 bytes long can be split, so that the
+    /// shares can be written down
+    InvalidSecretLength,
+    /// No shares were given to rebuild the secret
+    NoShares,
+    /// The share (by id) was given more than once
+    DuplicateShare(String),
+    /// The shares (by id) are from different splits
+    MixedShares(String, String),
+    /// Fewer shares were given than needed to rebuild the secret
+    NotEnoughShares {
+        needed: u8,
+        given: usize,
+    },
+    /// The entry already has an attachment with this name
+    AttachmentExists(String),
+    /// The entry has no attachment with this name
+    AttachmentNotFound(String),
+    /// The file is larger than the limit of a single attachment
+    AttachmentTooLarge {
+        name: String,
+        limit: usize,
+    },
+    /// The attachments of the vault would be larger than the limit of all
+    /// the attachments together
+    AttachmentsTooLarge {
+        limit: usize,
+    },
+    /// Padded vaults cannot have attachments, which would tell the entries
+    /// they belong to and their size
+    PaddedAttachments,
+    /// The vaults of deniable files only have a master password (no key
+    /// file, challenge or recovery slots)
+    DeniableVault,
+    /// The password of a vault in a deniable file would open another vault
+    /// of the file
+    DecoyPassword,
+    /// The key of the user cannot open the team vault
+    NotAMember,
+    /// The vault is not a team vault, so it has no members
+    NotATeamVault,
+    /// The operation needs a master password, which team vaults do not have
+    TeamVault,
+    /// There is already a member of the team vault with this name
+    MemberExists(String),
+    /// There is no member of the team vault with this name
+    MemberNotFound(String),
+    /// The only member of a team vault cannot be removed, as nobody could
+    /// open it afterwards
+    LastMember,
+    /// There is already a group of the team vault with this name
+    GroupExists(String),
+    /// There is no group of the team vault with this name
+    GroupNotFound(String),
+    /// The member who unlocked the team vault is not in this group
+    NotInGroup(String),
+    /// The entry belongs to a group the member who unlocked the team vault
+    /// is not in
+    PermissionDenied {
+        entry: String,
+        group: String,
+    },
+    /// The public key is not a valid X25519 or RSA key
+    InvalidKey,
+    /// The data file is damaged, or is not a data file at all
+    Corrupt {
+        reason: String,
+        source: Option<Source>,
+    },
+    /// The data file was written by a newer version of the program, with a
+    /// layout this version does not know
+    UnsupportedVersion(Version),
+    Io(std::io::Error),
+    /// Failure of the cryptographic library other than a wrong password
+    Crypto(openssl::error::ErrorStack),
+}
+
+impl Error {
+    pub(crate) fn corrupt(reason: impl Into<String>, source: impl Into<Source>) -> Self {
+        Error::Corrupt {
+            reason: reason.into(),
+            source: Some(source.into()),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::WrongPassword => write!(f, "Password does not match!"),
+            Error::EntryExists(name) => write!(f, "Entry `{}` already exists.", name),
+            Error::EntryNotFound(name) => write!(f, "Entry `{}` not found.", name),
+            Error::ReadOnly => write!(f, "Vault was opened read-only."),
+            Error::KeyFileRequired => write!(f, "A key file is required to unlock this vault."),
+            Error::ResponseRequired => write!(
+                f,
+                "The response to the challenge of this vault is required to unlock it."
+            ),
+            Error::WrongResponse => write!(f, "Response to the challenge does not match!"),
+            Error::PasswordRequired => write!(f, "The master password of the vault is required."),
+            Error::InvalidRecoveryKey => write!(f, "Invalid recovery key (check it for typos)."),
+            Error::WrongRecoveryKey => write!(
+                f,
+                "The recovery key does not open this vault (its slot may have been revoked)."
+            ),
+            Error::SlotNotFound(id) => write!(f, "Recovery key slot `{}` not found.", id),
+            Error::InvalidShare => write!(f, "Invalid share (check it for typos)."),
+            Error::InvalidThreshold => write!(
+                f,
+                "The number of shares needed must be between 2 and the number of shares."
+            ),
+            Error::InvalidSecretLength => {
+                write!(f, "The secret to split must be a multiple of 5 bytes long.")
+            }
+            Error::NoShares => write!(f, "No shares given."),
+            Error::DuplicateShare(id) => write!(f, "Share `{}` was given twice.", id),
+            Error::MixedShares(first, other) => write!(
+                f,
+                "Shares `{}` and `{}` are from different splits.",
+                first, other
+            ),
+            Error::NotEnoughShares { needed, given } => write!(
+                f,
+                "{} shares are needed, but only {} were given.",
+                needed, given
+            ),
+            Error::AttachmentExists(name) => write!(f, "Attachment `{}` already exists.", name),
+            Error::AttachmentNotFound(name) => write!(f, "Attachment `{}` not found.", name),
+            Error::AttachmentTooLarge { name, limit } => write!(
+                f,
+                "Attachment `{}` is larger than the limit of {} KiB.",
+                name,
+                limit / 1024
+            ),
+            Error::AttachmentsTooLarge { limit } => write!(
+                f,
+                "The attachments of the vault would be larger than the limit of {} KiB.",
+                limit / 1024
+            ),
+            Error::PaddedAttachments => write!(
+                f,
+                "Padded vaults cannot have attachments, as the data file would tell the entries they belong to and their size."
+            ),
+            Error::DeniableVault => write!(
+                f,
+                "Vaults of deniable files only have a master password, without key files, challenges or recovery keys."
+            ),
+            Error::DecoyPassword => write!(
+                f,
+                "The password must be different from the one of the other vault of the file."
+            ),
+            Error::NotAMember => write!(f, "You are not a member of this team vault."),
+            Error::NotATeamVault => write!(f, "This is not a team vault."),
+            Error::TeamVault => write!(f, "This is a team vault, which has no master password."),
+            Error::MemberExists(name) => write!(f, "Member `{}` already exists.", name),
+            Error::MemberNotFound(name) => write!(f, "Member `{}` not found.", name),
+            Error::LastMember => write!(
+                f,
+                "The last member of a team vault cannot be removed, as nobody could open it."
+            ),
+            Error::GroupExists(name) => write!(f, "Group `{}` already exists.", name),
+            Error::GroupNotFound(name) => write!(f, "Group `{}` not found.", name),
+            Error::NotInGroup(name) => write!(f, "You are not a member of group `{}`.", name),
+            Error::PermissionDenied { entry, group } => write!(
+                f,
+                "Entry `{}` belongs to group `{}`, which you are not a member of.",
+                entry, group
+            ),
+            Error::InvalidKey => {
+                write!(f, "Invalid public key (X25519 and RSA keys are supported).")
+            }
+            Error::Corrupt { reason, .. } => write!(f, "Data file is corrupted: {}.", reason),
+            Error::UnsupportedVersion(version) => write!(
+                f,
+                "Data file was created by version {}.{}.{}, which is not supported by \
+                 version {}.",
+                version[0],
+                version[1],
+                version[2],
+                env!("CARGO_PKG_VERSION")
+            ),
+            Error::Io(_) => write!(f, "Could not read or write data."),
+            Error::Crypto(_) => write!(f, "Cryptographic operation failed."),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Corrupt {
+                source: Some(source),
+                ..
+            } => Some(source.as_ref()),
+            Error::Io(e) => Some(e),
+            Error::Crypto(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<openssl::error::ErrorStack> for Error {
+    fn from(e: openssl::error::ErrorStack) -> Self {
+        Error::Crypto(e)
+    }
+}
+
+/// Serializing into memory does not fail, so bincode errors come from data
+/// that cannot be decoded (including truncated data).
+impl From<bincode::Error> for Error {
+    fn from(e: bincode::Error) -> Self {
+        Error::corrupt("invalid layout", e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error as _;
+
+    #[test]
+    fn sources_are_chained() {
+        let e: Error = std::io::Error::new(std::io::ErrorKind::NotFound, "no file").into();
+        assert_eq!(e.source().unwrap().to_string(), "no file");
+
+        let e: Error = bincode::deserialize::<String>(&[1]).unwrap_err().into();
+        assert!(matches!(e, Error::Corrupt { .. }));
+        assert!(e.source().is_some());
+
+        assert!(Error::WrongPassword.source().is_none());
+    }
+}
@@ -0,0 +1,353 @@
+//! Shamir's secret sharing over GF(256), to hand a recovery key (see
+//! `recovery`) to several people, so that any `threshold` of them together
+//! can open the vault, but fewer learn nothing about it.
+//!
+//! Each byte of the secret is the constant term of a random polynomial of
+//! degree `threshold - 1`, and share `x` holds the value of each polynomial
+//! at `x`. Shares are written like recovery keys (base32 with a checksum),
+//! along with the identifier of the split they belong to, the threshold and
+//! their position.
+
+use crate::crypto::{self, SecretBytes, SecretString};
+use crate::error::{Error, Result};
+use crate::recovery;
+
+/// Size of the identifier telling the shares of different splits apart.
+/// Along with the threshold and the index, the head of a share is 5 bytes
+/// long, so that shares of secrets a multiple of 5 bytes long can be encoded.
+const SET_LEN: usize = 3;
+
+/// Share of a secret
+pub struct Share {
+    /// Identifier of the split the share belongs to
+    set: [u8; SET_LEN],
+    /// Number of shares needed to rebuild the secret
+    pub threshold: u8,
+    /// Point where the polynomials were evaluated, from 1
+    pub index: u8,
+    /// Values of the polynomials at `index`, one per byte of the secret
+    value: SecretBytes,
+}
+
+impl Share {
+    /// Identifier of the share: the one of its split, and its position
+    pub fn id(&self) -> String {
+        let set: String = self
+            .set
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        format!("{}-{}", set, self.index)
+    }
+
+    /// The share as text, to be written down. It has its identifier, the
+    /// threshold and a checksum, like recovery keys.
+    pub fn encode(&self) -> SecretString {
+        let mut bytes = SecretBytes::zeroed(SET_LEN + 2 + self.value.len());
+        bytes[..SET_LEN].copy_from_slice(&self.set);
+        bytes[SET_LEN] = self.threshold;
+        bytes[SET_LEN + 1] = self.index;
+        bytes[SET_LEN + 2..].copy_from_slice(&self.value);
+
+        recovery::encode_checked(&bytes)
+    }
+
+    /// Reads a share written by `encode`, ignoring case, spaces and dashes.
+    pub fn parse(text: &str) -> Result<Self> {
+        let bytes = match recovery::decode_checked(text) {
+            Some(bytes) if bytes.len() > SET_LEN + 2 => bytes,
+            _ => return Err(Error::InvalidShare),
+        };
+
+        let mut set = [0; SET_LEN];
+        set.copy_from_slice(&bytes[..SET_LEN]);
+        let (threshold, index) = (bytes[SET_LEN], bytes[SET_LEN + 1]);
+        if threshold < 2 || index == 0 {
+            return Err(Error::InvalidShare);
+        }
+
+        Ok(Share {
+            set,
+            threshold,
+            index,
+            value: SecretBytes::from(&bytes[SET_LEN + 2..]),
+        })
+    }
+}
+
+/// Splits `secret`, which must be a multiple of 5 bytes long, into `count`
+/// shares, any `threshold` of which rebuild it.
+pub fn split(secret: &[u8], threshold: u8, count: u8) -> Result<Vec<Share>> {
+    if threshold < 2 || threshold > count {
+        return Err(Error::InvalidThreshold);
+    }
+    if !secret.len().is_multiple_of(5) {
+        return Err(Error::InvalidSecretLength);
+    }
+
+    let mut set = [0; SET_LEN];
+    set.copy_from_slice(&crypto::generate_bytes(SET_LEN));
+
+    // coefficients of the polynomial of each byte, constant term first
+    let coefficients: Vec<SecretBytes> = secret
+        .iter()
+        .map(|byte| {
+            let mut coefficients = SecretBytes::zeroed(threshold as usize);
+            coefficients[0] = *byte;
+            coefficients[1..].copy_from_slice(&crypto::generate_bytes(threshold as usize - 1));
+            coefficients
+        })
+        .collect();
+
+    Ok((1..=count)
+        .map(|index| {
+            let mut value = SecretBytes::zeroed(secret.len());
+            for (byte, coefficients) in value.iter_mut().zip(&coefficients) {
+                *byte = evaluate(coefficients, index);
+            }
+
+            Share {
+                set,
+                threshold,
+                index,
+                value,
+            }
+        })
+        .collect())
+}
+
+/// Rebuilds the secret from `shares`, which must be at least as many as
+/// their threshold, from the same split and in different positions.
+pub fn combine(shares: &[Share]) -> Result<SecretBytes> {
+    let first = shares.first().ok_or(Error::NoShares)?;
+
+    for (i, share) in shares.iter().enumerate() {
+        if share.set != first.set
+            || share.threshold != first.threshold
+            || share.value.len() != first.value.len()
+        {
+            return Err(Error::MixedShares(first.id(), share.id()));
+        }
+        if shares[..i].iter().any(|other| other.index == share.index) {
+            return Err(Error::DuplicateShare(share.id()));
+        }
+    }
+
+    if shares.len() < first.threshold as usize {
+        return Err(Error::NotEnoughShares {
+            needed: first.threshold,
+            given: shares.len(),
+        });
+    }
+
+    let shares = &shares[..first.threshold as usize];
+    let mut secret = SecretBytes::zeroed(first.value.len());
+
+    // Lagrange interpolation at 0
+    for share in shares {
+        let mut basis = 1;
+        for other in shares.iter().filter(|other| other.index != share.index) {
+            basis = mul(basis, div(other.index, other.index ^ share.index));
+        }
+
+        for (byte, value) in secret.iter_mut().zip(share.value.iter()) {
+            *byte ^= mul(basis, *value);
+        }
+    }
+
+    Ok(secret)
+}
+
+/// Value at `x` of the polynomial with `coefficients`, constant term first
+fn evaluate(coefficients: &[u8], x: u8) -> u8 {
+    coefficients
+        .iter()
+        .rev()
+        .fold(0, |value, coefficient| mul(value, x) ^ coefficient)
+}
+
+/// Product in GF(256), with the polynomial of AES (x^8 + x^4 + x^3 + x + 1).
+/// It takes the same time for all operands, which are secret.
+fn mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+
+    for _ in 0..8 {
+        product ^= a & 0u8.wrapping_sub(b & 1);
+        let carry = 0u8.wrapping_sub(a >> 7);
+        a = (a << 1) ^ (0x1b & carry);
+        b >>= 1;
+    }
+
+    product
+}
+
+/// Inverse in GF(256) of a non-zero `a`: a^254
+fn inv(a: u8) -> u8 {
+    let mut result = 1;
+    let mut power = a;
+
+    for bit in 0..8 {
+        if (254 >> bit) & 1 == 1 {
+            result = mul(result, power);
+        }
+        power = mul(power, power);
+    }
+
+    result
+}
+
+fn div(a: u8, b: u8) -> u8 {
+    mul(a, inv(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Product computed the slow way: multiplying the polynomials, then
+    /// reducing the result
+    fn reference_mul(a: u8, b: u8) -> u8 {
+        let mut product: u16 = 0;
+        for bit in 0..8 {
+            if (b >> bit) & 1 == 1 {
+                product ^= (a as u16) << bit;
+            }
+        }
+        for bit in (8..16).rev() {
+            if (product >> bit) & 1 == 1 {
+                product ^= 0x11b << (bit - 8);
+            }
+        }
+        product as u8
+    }
+
+    #[test]
+    fn field_operations_are_correct() {
+        for a in 0..=255 {
+            for b in 0..=255 {
+                assert_eq!(mul(a, b), reference_mul(a, b), "{} * {}", a, b);
+            }
+        }
+
+        assert_eq!(mul(0x57, 0x83), 0xc1); // FIPS 197, section 4.2
+        for a in 1..=255 {
+            assert_eq!(mul(a, inv(a)), 1, "inverse of {}", a);
+            assert_eq!(div(a, a), 1);
+        }
+    }
+
+    /// Subsets of `0..n`, as lists of indexes
+    fn subsets(n: u8) -> impl Iterator<Item = Vec<usize>> {
+        (0u32..1 << n).map(move |mask| (0..n as usize).filter(|i| mask >> i & 1 == 1).collect())
+    }
+
+    #[test]
+    fn any_threshold_shares_rebuild_the_secret() {
+        let secret = crypto::generate_bytes(25);
+
+        for count in 2..=7 {
+            for threshold in 2..=count {
+                let shares = split(&secret, threshold, count).unwrap();
+
+                for subset in subsets(count) {
+                    let chosen: Vec<Share> = subset
+                        .iter()
+                        .map(|&i| Share::parse(&shares[i].encode()).unwrap())
+                        .collect();
+
+                    match combine(&chosen) {
+                        Ok(combined) => {
+                            assert!(chosen.len() >= threshold as usize);
+                            assert_eq!(&combined[..], &secret[..]);
+                        }
+                        Err(_) => assert!(chosen.len() < threshold as usize),
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn every_byte_is_shared() {
+        let secret: Vec<u8> = (0..=255).chain(0..4).collect();
+        let shares = split(&secret, 2, 3).unwrap();
+
+        for (i, j) in [(0, 1), (0, 2), (1, 2), (2, 0)] {
+            let pair = [
+                Share::parse(&shares[i].encode()).unwrap(),
+                Share::parse(&shares[j].encode()).unwrap(),
+            ];
+            assert_eq!(&combine(&pair).unwrap()[..], &secret[..]);
+        }
+    }
+
+    #[test]
+    fn fewer_shares_than_the_threshold_reveal_nothing() {
+        // with threshold 2, for any secret byte, the value of a single share
+        // over the random slope takes each value once: it is uniform, the
+        // same for all secrets
+        for secret in 0..=255 {
+            for x in 1..=255 {
+                let mut count = [0u8; 256];
+                for slope in 0..=255 {
+                    count[evaluate(&[secret, slope], x) as usize] += 1;
+                }
+                assert!(count.iter().all(|&n| n == 1), "{} at {}", secret, x);
+            }
+        }
+
+        // with threshold 3, the same holds for one share, and for the pair
+        // of values of two shares
+        for secret in [0, 1, 0x53, 0xca, 255] {
+            for (x, y) in [(1, 2), (3, 255), (0x80, 0x81)] {
+                let mut single = vec![0u32; 256];
+                let mut pairs = vec![0u8; 256 * 256];
+                for a in 0..=255 {
+                    for b in 0..=255 {
+                        let at_x = evaluate(&[secret, a, b], x) as usize;
+                        let at_y = evaluate(&[secret, a, b], y) as usize;
+                        single[at_x] += 1;
+                        pairs[at_x * 256 + at_y] += 1;
+                    }
+                }
+                assert!(single.iter().all(|&n| n == 256));
+                assert!(pairs.iter().all(|&n| n == 1));
+            }
+        }
+    }
+
+    #[test]
+    fn mismatched_shares_are_refused() {
+        let secret = crypto::generate_bytes(25);
+        let shares = split(&secret, 2, 3).unwrap();
+        let others = split(&secret, 2, 3).unwrap();
+        let parse = |share: &Share| Share::parse(&share.encode()).unwrap();
+
+        assert!(matches!(
+            combine(&[parse(&shares[0]), parse(&others[1])]),
+            Err(Error::MixedShares(..))
+        ));
+        assert!(matches!(
+            combine(&[parse(&shares[0]), parse(&shares[0])]),
+            Err(Error::DuplicateShare(_))
+        ));
+        assert!(matches!(
+            combine(&[parse(&shares[0])]),
+            Err(Error::NotEnoughShares {
+                needed: 2,
+                given: 1
+            })
+        ));
+        assert!(matches!(combine(&[]), Err(Error::NoShares)));
+        assert!(matches!(split(&secret, 1, 3), Err(Error::InvalidThreshold)));
+        assert!(matches!(split(&secret, 4, 3), Err(Error::InvalidThreshold)));
+        assert!(matches!(
+            split(&secret[..24], 2, 3),
+            Err(Error::InvalidSecretLength)
+        ));
+
+        let text = shares[0].encode().to_string();
+        let typo = text.replacen(&text[..1], if text.starts_with('A') { "B" } else { "A" }, 1);
+        assert!(matches!(Share::parse(&typo), Err(Error::InvalidShare)));
+    }
+}
@@ -1,83 +1,351 @@
-use anyhow::Result;
+//! Locations and reading/writing of files. Following the XDG base directory
+//! specification, data files are kept in `$XDG_DATA_HOME`, the configuration
+//! in `$XDG_CONFIG_HOME` and sockets in `$XDG_RUNTIME_DIR`.
+
+use anyhow::{anyhow, bail, Result};
 use std::io::Write;
 use std::path::{Path, PathBuf};
 
-const PATH_ENV: &str = "GUARAKAPA_FILE_PATH";
+pub const PATH_ENV: &str = "GUARAKAPA_FILE_PATH";
+const CONFIG_PATH_ENV: &str = "GUARAKAPA_CONFIG_PATH";
+const DATA_HOME_ENV: &str = "XDG_DATA_HOME";
+const CONFIG_HOME_ENV: &str = "XDG_CONFIG_HOME";
+const RUNTIME_DIR_ENV: &str = "XDG_RUNTIME_DIR";
+const HOME_ENV: &str = "HOME";
 const DEFAULT_FILENAME: &str = "gk.dat";
+const CONFIG_FILENAME: &str = "config.toml";
 
-#[cfg(not(debug_assertions))]
-fn data_dir() -> PathBuf {
-    match std::env::var(PATH_ENV) {
-        Ok(path) => Path::new(&path)
-            .parent()
-            .unwrap_or(Path::new("."))
-            .to_path_buf(),
-        Err(_) => {
-            let home = std::env::var("HOME").unwrap();
-            Path::new(&home)
-                .join(".config")
-                .join(env!("CARGO_PKG_NAME"))
-        }
+/// Directory of the data files, `$XDG_DATA_HOME/guarakapa` (by default
+/// `~/.local/share/guarakapa`)
+pub fn data_dir() -> Result<PathBuf> {
+    base_dir(DATA_HOME_ENV, &[".local", "share"])
+}
+
+/// Directory of the configuration file, `$XDG_CONFIG_HOME/guarakapa` (by
+/// default `~/.config/guarakapa`)
+pub fn config_dir() -> Result<PathBuf> {
+    base_dir(CONFIG_HOME_ENV, &[".config"])
+}
+
+/// Directory of sockets and other files that only live while the user is
+/// logged in, `$XDG_RUNTIME_DIR/guarakapa`. There is no default, as it must
+/// be private to the user.
+pub fn runtime_dir() -> Result<PathBuf> {
+    match absolute_env_path(RUNTIME_DIR_ENV) {
+        Some(dir) => Ok(dir.join(env!("CARGO_PKG_NAME"))),
+        None => bail!("{} is not set.", RUNTIME_DIR_ENV),
     }
 }
 
-// for debug and tests
-#[cfg(debug_assertions)]
-fn data_dir() -> PathBuf {
-    match std::env::var(PATH_ENV) {
-        Ok(path) => Path::new(&path).parent().unwrap().to_path_buf(),
-        _ => Path::new(".").to_path_buf(),
+/// Directory of the program under the base directory in the environment
+/// variable `env`, or under `components` of the home directory if it is not
+/// set. Relative paths are ignored, as the XDG specification requires.
+fn base_dir(env: &str, components: &[&str]) -> Result<PathBuf> {
+    let base = match absolute_env_path(env) {
+        Some(dir) => dir,
+        None => components.iter().fold(home_dir()?, |dir, c| dir.join(c)),
+    };
+
+    Ok(base.join(env!("CARGO_PKG_NAME")))
+}
+
+fn absolute_env_path(env: &str) -> Option<PathBuf> {
+    std::env::var_os(env)
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+}
+
+fn home_dir() -> Result<PathBuf> {
+    absolute_env_path(HOME_ENV).ok_or_else(|| anyhow!("{} is not set.", HOME_ENV))
+}
+
+/// Directory of the data file of versions up to 0.11, which also held the
+/// configuration file
+fn legacy_dir() -> Result<PathBuf> {
+    Ok(home_dir()?.join(".config").join(env!("CARGO_PKG_NAME")))
+}
+
+/// Path of the default data file (the one used when no vault is selected):
+/// `$GUARAKAPA_FILE_PATH` if set, otherwise `gk.dat` in the data directory.
+pub fn default_file_path() -> Result<PathBuf> {
+    match std::env::var_os(PATH_ENV) {
+        Some(path) => Ok(PathBuf::from(path)),
+        None => Ok(data_dir()?.join(DEFAULT_FILENAME)),
     }
 }
 
-fn get_filename() -> String {
-    match std::env::var(PATH_ENV) {
-        Ok(path) => match Path::new(&path).file_name() {
-            Some(filename) => filename.to_string_lossy().into_owned(),
-            _ => DEFAULT_FILENAME.to_string(),
-        },
-        _ => DEFAULT_FILENAME.to_string(),
+/// Path of the configuration file: `$GUARAKAPA_CONFIG_PATH` if set,
+/// otherwise `config.toml` in the configuration directory.
+pub fn config_path() -> Result<PathBuf> {
+    match std::env::var_os(CONFIG_PATH_ENV) {
+        Some(path) => Ok(PathBuf::from(path)),
+        None => Ok(config_dir()?.join(CONFIG_FILENAME)),
     }
 }
 
-fn get_file_full_path() -> PathBuf {
-    data_dir().join(get_filename())
+/// Moves the default data file (with its backups) and the configuration
+/// file from the directory used by older versions to the XDG directories,
+/// unless there are files there already. This is only done once, while the
+/// data directory does not exist: it is created afterwards. Returns the
+/// paths of the files moved.
+pub fn migrate_legacy_files() -> Result<Vec<PathBuf>> {
+    let data_dir = data_dir()?;
+    if data_dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let legacy_dir = match legacy_dir() {
+        Ok(dir) if dir.exists() => dir,
+        _ => return Ok(Vec::new()),
+    };
+    let mut moved = Vec::new();
+
+    if std::env::var_os(PATH_ENV).is_none() {
+        let legacy = legacy_dir.join(DEFAULT_FILENAME);
+        moved.extend(migrate(&legacy, &default_file_path()?)?);
+    }
+    if std::env::var_os(CONFIG_PATH_ENV).is_none() {
+        let legacy = legacy_dir.join(CONFIG_FILENAME);
+        moved.extend(migrate(&legacy, &config_path()?)?);
+    }
+
+    create_dir(&data_dir)?;
+    Ok(moved)
 }
 
-fn create_dir() -> Result<()> {
-    if data_dir().exists() {
+/// Moves the file at `from` and its backups to `to`, if `from` exists and
+/// `to` does not. Returns the paths of the files moved.
+fn migrate(from: &Path, to: &Path) -> Result<Vec<PathBuf>> {
+    if from == to || !from.exists() || to.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut moved = Vec::new();
+    let backups = (1..).map(|n| (backup_path(from, n), backup_path(to, n)));
+
+    for (from, to) in std::iter::once((from.to_path_buf(), to.to_path_buf()))
+        .chain(backups.take_while(|(from, _)| from.exists()))
+    {
+        move_file(&from, &to)?;
+        moved.push(from);
+    }
+
+    Ok(moved)
+}
+
+/// Renames a file, copying it if it must go to another file system
+fn move_file(from: &Path, to: &Path) -> Result<()> {
+    if let Some(dir) = to.parent() {
+        create_dir(dir)?;
+    }
+
+    if std::fs::rename(from, to).is_err() {
+        std::fs::copy(from, to)?;
+        std::fs::remove_file(from)?;
+    }
+
+    Ok(())
+}
+
+fn create_dir(dir: &Path) -> std::io::Result<()> {
+    if dir.as_os_str().is_empty() || dir.exists() {
         return Ok(());
     }
 
-    std::fs::DirBuilder::new()
-        .recursive(true)
-        .create(data_dir())?;
+    std::fs::DirBuilder::new().recursive(true).create(dir)?;
     Ok(())
 }
 
-pub fn file_path() -> String {
-    get_file_full_path().to_string_lossy().to_string()
+pub fn file_path() -> Result<String> {
+    Ok(default_file_path()?.to_string_lossy().to_string())
 }
 
 pub fn file_exists() -> bool {
-    get_file_full_path().exists()
+    default_file_path().is_ok_and(|path| path.exists())
 }
 
+/// Writes `contents` to the default data file (see `save_atomically`)
 pub fn save(contents: Vec<u8>) -> Result<()> {
-    create_dir()?;
-    let path = get_file_full_path();
-    let mut file_handle = std::fs::File::create(path)?;
-    file_handle.write_all(contents.as_slice())?;
+    Ok(save_atomically(default_file_path()?, contents)?)
+}
+
+/// Writes `contents` to a new file at `path`, readable and writable only by
+/// the user (e.g. a key file). Fails if there is a file at `path` already.
+pub fn save_private(path: impl AsRef<Path>, contents: &[u8]) -> std::io::Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut file_handle = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    file_handle.write_all(contents)
+}
+
+/// Writes `contents` to the file at `path` so that it is never left
+/// partially written (e.g. if the program is interrupted): they are written
+/// to a temporary file next to it, which then replaces it. A new file is only
+/// readable and writable by the user, while a file at `path` keeps its
+/// permissions, and is followed if it is a symbolic link.
+pub fn save_atomically(path: impl AsRef<Path>, contents: Vec<u8>) -> std::io::Result<()> {
+    let path = match std::fs::canonicalize(path.as_ref()) {
+        Ok(target) => target,
+        Err(_) => path.as_ref().to_path_buf(),
+    };
+    if let Some(dir) = path.parent() {
+        create_dir(dir)?;
+    }
+
+    // a unique name, so that concurrent saves do not write to the same file
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(format!(
+        ".{}.{:016x}.tmp",
+        std::process::id(),
+        rand::random::<u64>()
+    ));
+    let temp_path = PathBuf::from(temp_path);
+
+    let result =
+        write_temp(&temp_path, &contents, &path).and_then(|()| std::fs::rename(&temp_path, &path));
+    if let Err(e) = result {
+        let _ = std::fs::remove_file(&temp_path);
+        return Err(e);
+    }
+
+    // the rename itself is only durable once the directory is written
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => std::fs::File::open(dir)?.sync_all(),
+        _ => Ok(()),
+    }
+}
+
+/// Writes the temporary file of `save_atomically`, created readable only by
+/// the user and then given the permissions of the file at `path` (if any),
+/// and flushes it to disk.
+fn write_temp(temp_path: &Path, contents: &[u8], path: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut temp_file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(temp_path)?;
+    temp_file.write_all(contents)?;
+
+    if let Ok(metadata) = std::fs::metadata(path) {
+        temp_file.set_permissions(metadata.permissions())?;
+    }
+    temp_file.sync_all()
+}
+
+/// Path of the `n`-th backup of the file at `path`
+pub fn backup_path(path: impl AsRef<Path>, n: usize) -> PathBuf {
+    let mut name = path.as_ref().as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+/// Copies the file at `path` (if any) to `PATH.1`, keeping the previous
+/// `keep - 1` backups as `PATH.2` to `PATH.keep`.
+pub fn backup(path: impl AsRef<Path>, keep: usize) -> Result<()> {
+    let path = path.as_ref();
+
+    if keep == 0 || !path.exists() {
+        return Ok(());
+    }
+
+    for n in (1..keep).rev() {
+        if backup_path(path, n).exists() {
+            std::fs::rename(backup_path(path, n), backup_path(path, n + 1))?;
+        }
+    }
+
+    std::fs::copy(path, backup_path(path, 1))?;
     Ok(())
 }
 
 pub fn load() -> Result<Vec<u8>> {
-    let path = get_file_full_path();
+    let path = default_file_path()?;
     let content = std::fs::read(path)?;
     Ok(content)
 }
 
-pub fn load_from(path: &str) -> Result<Vec<u8>> {
-    let content = std::fs::read(path)?;
-    Ok(content)
+pub fn load_from(path: impl AsRef<Path>) -> std::io::Result<Vec<u8>> {
+    std::fs::read(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("gk-fs-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn migrates_file_with_backups() {
+        let dir = temp_dir("migrate");
+        let from = dir.join("legacy").join(DEFAULT_FILENAME);
+        let to = dir.join("data").join(DEFAULT_FILENAME);
+
+        save_atomically(&from, b"current".to_vec()).unwrap();
+        save_atomically(backup_path(&from, 1), b"previous".to_vec()).unwrap();
+
+        assert_eq!(migrate(&from, &to).unwrap().len(), 2);
+        assert!(!from.exists());
+        assert_eq!(load_from(&to).unwrap(), b"current");
+        assert_eq!(load_from(backup_path(&to, 1)).unwrap(), b"previous");
+
+        // nothing left to move
+        assert!(migrate(&from, &to).unwrap().is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn atomic_save_replaces_file() {
+        let dir = temp_dir("atomic");
+        let path = dir.join(DEFAULT_FILENAME);
+
+        save_atomically(&path, b"first".to_vec()).unwrap();
+        save_atomically(&path, b"second".to_vec()).unwrap();
+
+        assert_eq!(load_from(&path).unwrap(), b"second");
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn atomic_save_keeps_files_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = temp_dir("private");
+        let path = dir.join(DEFAULT_FILENAME);
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+        save_atomically(&path, b"first".to_vec()).unwrap();
+        assert_eq!(mode(&path), 0o600);
+
+        // the permissions chosen by the user are kept
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o640)).unwrap();
+        save_atomically(&path, b"second".to_vec()).unwrap();
+        assert_eq!(mode(&path), 0o640);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn does_not_replace_existing_files() {
+        let dir = temp_dir("existing");
+        let from = dir.join("legacy").join(DEFAULT_FILENAME);
+        let to = dir.join("data").join(DEFAULT_FILENAME);
+
+        save_atomically(&from, b"old".to_vec()).unwrap();
+        save_atomically(&to, b"new".to_vec()).unwrap();
+
+        assert!(migrate(&from, &to).unwrap().is_empty());
+        assert_eq!(load_from(&from).unwrap(), b"old");
+        assert_eq!(load_from(&to).unwrap(), b"new");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
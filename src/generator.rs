@@ -0,0 +1,133 @@
+//! Random password generation.
+
+use crate::crypto::{SecretBytes, SecretString};
+use anyhow::{bail, Result};
+use rand::{rngs::OsRng, Rng};
+use serde::{Deserialize, Serialize};
+
+const LOWERCASE: &[u8] = b"abcdefghijklmnopqrstuvwxyz";
+const UPPERCASE: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+const DIGITS: &[u8] = b"0123456789";
+const SYMBOLS: &[u8] = b"!#$%&()*+,-./:;<=>?@[]^_{|}~";
+
+/// Character classes and length of generated passwords
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Options {
+    pub length: usize,
+    pub lowercase: bool,
+    pub uppercase: bool,
+    pub digits: bool,
+    pub symbols: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            length: 20,
+            lowercase: true,
+            uppercase: true,
+            digits: true,
+            symbols: true,
+        }
+    }
+}
+
+impl Options {
+    fn classes(&self) -> Vec<&'static [u8]> {
+        [
+            (self.lowercase, LOWERCASE),
+            (self.uppercase, UPPERCASE),
+            (self.digits, DIGITS),
+            (self.symbols, SYMBOLS),
+        ]
+        .into_iter()
+        .filter(|(enabled, _)| *enabled)
+        .map(|(_, class)| class)
+        .collect()
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        let classes = self.classes().len();
+
+        if classes == 0 {
+            bail!("At least one character class must be enabled.");
+        }
+        if self.length < classes.max(4) || self.length > 1024 {
+            bail!(
+                "Password length must be between {} and 1024.",
+                classes.max(4)
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Generates a password with characters drawn uniformly from the enabled
+/// classes, containing at least one character of each class.
+pub fn generate(options: &Options) -> Result<SecretString> {
+    options.validate()?;
+
+    let classes = options.classes();
+    let charset: Vec<u8> = classes.concat();
+    let mut password = SecretBytes::zeroed(options.length);
+
+    loop {
+        for byte in password.iter_mut() {
+            *byte = charset[OsRng.gen_range(0..charset.len())];
+        }
+
+        if classes
+            .iter()
+            .all(|class| password.iter().any(|byte| class.contains(byte)))
+        {
+            break;
+        }
+    }
+
+    // all characters are ASCII
+    Ok(std::str::from_utf8(&password)?.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passwords_follow_options() {
+        let options = Options {
+            length: 12,
+            symbols: false,
+            ..Default::default()
+        };
+
+        for _ in 0..50 {
+            let pw = generate(&options).unwrap();
+            assert_eq!(pw.len(), 12);
+            assert!(pw.chars().all(|c| c.is_ascii_alphanumeric()));
+            assert!(pw.chars().any(|c| c.is_ascii_digit()));
+            assert!(pw.chars().any(|c| c.is_ascii_uppercase()));
+        }
+
+        assert_ne!(generate(&options).unwrap(), generate(&options).unwrap());
+    }
+
+    #[test]
+    fn rejects_invalid_options() {
+        let no_classes = Options {
+            lowercase: false,
+            uppercase: false,
+            digits: false,
+            symbols: false,
+            ..Default::default()
+        };
+        assert!(generate(&no_classes).is_err());
+
+        let too_short = Options {
+            length: 3,
+            ..Default::default()
+        };
+        assert!(generate(&too_short).is_err());
+    }
+}
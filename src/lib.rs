@@ -1,4 +1,57 @@
+//! A password manager for the terminal. Besides the `kapa` program, this
+//! crate can be used as a library to read and change data files:
+//!
+//! ```
+//! use guarakapa::{Entry, Error, Vault};
+//!
+//! # let path = std::env::temp_dir().join(format!("gk-doc-lib-{}.dat", std::process::id()));
+//! # let _ = std::fs::remove_file(&path);
+//! # let mut vault = Vault::create(&path, "master password")?;
+//! # vault.add("github", Entry::new("hunter2"))?;
+//! # vault.save()?;
+//! let vault = Vault::open(&path)?.unlock("master password")?;
+//!
+//! match vault.get("github") {
+//!     Ok(entry) => println!("user: {}", entry.user),
+//!     Err(Error::EntryNotFound(name)) => println!("no entry `{}`", name),
+//!     Err(e) => return Err(e),
+//! }
+//! # std::fs::remove_file(&path)?;
+//! # Ok::<(), guarakapa::Error>(())
+//! ```
+//!
+//! # Stability
+//!
+//! The items exported at the root of the crate (`Vault`, `UnlockedVault`,
+//! `Entry`, `SecretString`, `Error` and `Result`) and the `vault` and `error`
+//! modules are the stable API, and follow semantic versioning: while the
+//! major version is `0`, breaking changes only come with a new minor version
+//! (e.g. from `0.19` to `0.20`), never in a patch release. Their structs and
+//! enums are `non_exhaustive`, so adding fields or error variants is not a
+//! breaking change.
+//!
+//! The other modules are used by the `kapa` and `kapa-agent` programs and
+//! may change in any release. Data files written by any version can be read
+//! by later versions.
+
+pub mod agent;
+pub mod audit;
+pub mod breach;
+pub mod challenge;
 pub mod codec;
+pub mod config;
 pub mod crypto;
+pub mod diff;
+pub mod error;
+pub mod escrow;
 pub mod fman;
 pub mod fs;
+pub mod generator;
+pub mod merge;
+pub mod recovery;
+pub mod sync;
+pub mod time;
+pub mod vault;
+
+pub use error::{Error, Result};
+pub use vault::{Entry, SecretString, UnlockedVault, Vault};
@@ -1,176 +1,1596 @@
 use guarakapa::{
-    codec, crypto,
-    fman::{self, File},
-    fs,
+    agent, audit, breach, challenge, codec,
+    config::{self, ClipboardBackend, Config, Field, OutputFormat},
+    crypto::{self, Keypair, SecretBytes, SecretString},
+    diff::{self, Change},
+    escrow::{self, Share},
+    fman::{self, Factors, File, OpenEntry, Padding, UnlockedVault},
+    fs, generator,
+    merge::{self, Conflict, Side},
+    recovery::RecoveryKey,
+    sync, time,
 };
 use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use zeroize::Zeroize;
 
 const MSG_ENTER_PW: &str = "Enter your master password: ";
 const MSG_SAVE_ERR: &str = "Failed to save file";
+const MSG_READ_ONLY: &str = "This command is not available in read-only mode.";
 const MSG_LOAD_ERR: &str = "Failed to load file";
-const MSG_WRONG_PW: &str = "Password does not match!";
 const MSG_ENCODE_ERR: &str = "Failed to encode file.";
-const MSG_DECODE_ERR: &str = "Failed to decode file.";
 
-fn get_input_pw(prompt: &str) -> String {
+/// Maximum length of a password typed by the user
+const MAX_PW_LEN: usize = 1024;
+
+/// Data file of the default vault, which keeps the keypair of the user to
+/// open team vaults
+static PERSONAL_VAULT_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+/// Factors given in the options (the key file of `--keyfile`), to unlock the
+/// data files requiring them
+static FACTORS: OnceLock<Factors> = OnceLock::new();
+
+/// Command giving the response to the challenge of a data file (the
+/// `challenge.command` setting)
+static CHALLENGE_COMMAND: OnceLock<String> = OnceLock::new();
+
+/// Whether `--read-only` was given: vaults are unlocked read-only, so that
+/// any change to them fails
+static READ_ONLY: OnceLock<bool> = OnceLock::new();
+
+/// Size of the random key files created by `keyfile create`
+const KEY_FILE_LEN: usize = 32;
+
+/// Field by which `ls` sorts the entries
+#[derive(Clone, Copy, PartialEq)]
+enum SortKey {
+    Name,
+    Created,
+    Modified,
+    PwChanged,
+    Used,
+}
+
+impl SortKey {
+    fn parse(key: &str) -> Option<Self> {
+        match key {
+            "name" => Some(SortKey::Name),
+            "created" => Some(SortKey::Created),
+            "modified" => Some(SortKey::Modified),
+            "pw-changed" => Some(SortKey::PwChanged),
+            "used" => Some(SortKey::Used),
+            _ => None,
+        }
+    }
+
+    fn timestamp(&self, entry: &OpenEntry) -> u64 {
+        match self {
+            SortKey::Name => 0,
+            SortKey::Created => entry.created,
+            SortKey::Modified => entry.modified,
+            SortKey::PwChanged => entry.pw_changed,
+            SortKey::Used => entry.last_used.unwrap_or(0),
+        }
+    }
+}
+
+fn get_input_pw(prompt: &str) -> SecretString {
     use std::io::Write;
-    use termion::input::TermRead;
 
-    let stdout = std::io::stdout();
-    let mut stdout = stdout.lock();
-    let stdin = std::io::stdin();
-    let mut stdin = stdin.lock();
+    // the prompt goes to stderr so that stdout can be piped (e.g. JSON output)
+    let mut stderr = std::io::stderr();
+    stderr.write_all(prompt.as_bytes()).unwrap();
+    stderr.flush().unwrap();
+
+    if let Ok(Some(pass)) = read_passwd() {
+        pass
+    } else {
+        panic!("Failed to read input!");
+    }
+}
+
+/// Reads a line from the terminal without echoing it. Unlike
+/// `termion::input::TermRead::read_passwd`, the input is read unbuffered
+/// into a secret buffer, so no copies of the password are left in memory.
+fn read_passwd() -> std::io::Result<Option<SecretString>> {
+    use std::io::Read;
+    use std::os::fd::AsFd;
+    use termion::raw::IntoRawMode;
+
+    let _raw = std::io::stderr().into_raw_mode()?;
+    let mut stdin = std::fs::File::from(std::io::stdin().as_fd().try_clone_to_owned()?);
+
+    let mut buffer = SecretBytes::zeroed(MAX_PW_LEN);
+    let mut len: usize = 0;
+    let mut byte = [0];
+
+    loop {
+        if stdin.read(&mut byte)? == 0 {
+            return Ok(None);
+        }
+
+        match byte[0] {
+            0 | 3 | 4 => return Ok(None),
+            // a whole character: its continuation bytes (0b10xx_xxxx), and
+            // the byte they follow
+            0x7f => {
+                while len > 0 {
+                    len -= 1;
+                    if buffer[len] & 0xc0 != 0x80 {
+                        break;
+                    }
+                }
+            }
+            b'\n' | b'\r' => break,
+            c if len < MAX_PW_LEN => {
+                buffer[len] = c;
+                len += 1;
+            }
+            _ => (),
+        }
+    }
+
+    match std::str::from_utf8(&buffer[..len]) {
+        Ok(pass) => Ok(Some(pass.into())),
+        Err(e) => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e)),
+    }
+}
+
+fn get_input() -> String {
+    let mut s = String::new();
+    std::io::stdin().read_line(&mut s).unwrap();
+    s.trim_end().to_owned()
+}
+
+/// Copies `text` to the clipboard and blocks until the user presses ENTER
+/// or the timeout runs out. When this function returns, the clipboard is
+/// cleared.
+fn copy_to_clipboard_and_block(text: &str, settings: &config::Clipboard) -> anyhow::Result<()> {
+    match settings.backend {
+        ClipboardBackend::X11 => {
+            // when the clipboard is dropped, its contents are dropped too
+            let clipboard = x11_clipboard::Clipboard::new()?;
+            clipboard.store(
+                clipboard.setter.atoms.clipboard,
+                clipboard.setter.atoms.utf8_string,
+                text.as_bytes(),
+            )?;
+
+            wait_for_enter(settings.timeout);
+        }
+        ClipboardBackend::Command => {
+            let command = settings.command.as_deref().unwrap_or_default();
+            run_clipboard_command(command, text)?;
+            wait_for_enter(settings.timeout);
+            run_clipboard_command(command, "")?;
+        }
+        ClipboardBackend::None => (),
+    }
+
+    Ok(())
+}
+
+/// Runs the shell command `command`, writing `input` to its standard input
+fn run_clipboard_command(command: &str, input: &str) -> anyhow::Result<()> {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    let mut child = Command::new("sh")
+        .args(["-c", command])
+        .stdin(Stdio::piped())
+        .spawn()?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input.as_bytes())?;
+    }
+
+    if !child.wait()?.success() {
+        anyhow::bail!("Clipboard command `{}` failed.", command);
+    }
+
+    Ok(())
+}
+
+/// Waits until the user presses ENTER, or for `timeout` seconds if it is
+/// not zero.
+fn wait_for_enter(timeout: u64) {
+    if timeout == 0 {
+        get_input();
+        return;
+    }
+
+    let (sender, receiver) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        get_input();
+        let _ = sender.send(());
+    });
+    let _ = receiver.recv_timeout(std::time::Duration::from_secs(timeout));
+}
+
+fn get_input_field(fieldname: &str) -> String {
+    println!(
+        "Enter {} for this entry (or just press ENTER to leave it blank):",
+        fieldname
+    );
+
+    get_input()
+}
+
+/// Creates the data file, asking for the master password. With `deniable`,
+/// the file is deniable (see `File::try_new_deniable`) and a decoy password
+/// is asked for as well, which can be left blank for no decoy.
+fn create_new_file(path: &Path, config: &Config, deniable: bool) {
+    if deniable && factors().key_file.is_some() {
+        println!("Vaults with a decoy password cannot require a key file.");
+        return;
+    }
+
+    let pw = get_input_pw("Enter a new master password: ");
+    println!();
+    let confirm = get_input_pw("Please repeat: ");
+    println!();
+
+    if pw != confirm {
+        println!("Password confirmation incorrect!");
+        return;
+    }
+
+    let decoy_pw = if deniable {
+        let decoy_pw = get_input_pw("Enter the decoy password (leave blank for no decoy): ");
+        println!();
+        if decoy_pw.is_empty() {
+            None
+        } else {
+            let confirm = get_input_pw("Please repeat: ");
+            println!();
+
+            if decoy_pw != confirm {
+                println!("Password confirmation incorrect!");
+                return;
+            }
+            if decoy_pw == pw {
+                println!("The decoy password must be different from the master password.");
+                return;
+            }
+            Some(decoy_pw)
+        }
+    } else {
+        None
+    };
+
+    let vault = if deniable {
+        File::try_new_deniable(&pw, decoy_pw.as_deref(), config.kdf.kdf())
+    } else {
+        File::try_new_with_factors(&pw, config.kdf.kdf(), factors())
+    };
+    let file = vault
+        .and_then(|vault| vault.lock())
+        .expect("Error creating new file.");
+    fs::save_atomically(path, codec::encode(&file).expect(MSG_ENCODE_ERR)).expect(MSG_SAVE_ERR);
+
+    println!(
+        "Your password file was created (at {}). \
+         Run the program again to add new entries.",
+        path.display()
+    );
+}
+
+/// Loads the data file and unlocks it with the master password typed by the
+/// user (or with the key kept by the agent).
+fn unlock_file(path: &Path) -> guarakapa::Result<UnlockedVault> {
+    unlock_file_with_prompt(path, MSG_ENTER_PW)
+}
+
+fn unlock_file_with_prompt(path: &Path, prompt: &str) -> guarakapa::Result<UnlockedVault> {
+    let mut vault = unlock_data_file(path, prompt)?;
+    if read_only() {
+        vault.set_read_only();
+    }
+
+    Ok(vault)
+}
+
+fn unlock_data_file(path: &Path, prompt: &str) -> guarakapa::Result<UnlockedVault> {
+    let contents = fs::load_from(path).expect(MSG_LOAD_ERR);
+    let file: File = codec::decode(contents.as_slice())?;
+
+    // the agent only keeps the vault key, which does not open groups, and
+    // which changes on every save when there is a challenge
+    if file.groups().is_empty() && file.challenge().is_none() {
+        if let Some(key) = key_from_agent(contents) {
+            return file.unlock_with_key(key);
+        }
+    }
+
+    if file.is_team() {
+        return unlock_team_file(path, file);
+    }
+
+    unlock_with_password(file, prompt)
+}
+
+/// Unlocks the data file with a master password typed by the user, and the
+/// factors given in the options.
+fn unlock_with_password(file: File, prompt: &str) -> guarakapa::Result<UnlockedVault> {
+    if file.requires_key_file() && factors().key_file.is_none() {
+        return Err(guarakapa::Error::KeyFileRequired);
+    }
+
+    let pw = get_input_pw(prompt);
+    eprintln!();
+
+    let factors = factors_for(&file)?;
+    file.unlock_with_factors(&pw, &factors)
+}
+
+fn factors() -> &'static Factors {
+    FACTORS.get_or_init(Factors::default)
+}
+
+fn read_only() -> bool {
+    READ_ONLY.get().copied().unwrap_or(false)
+}
+
+/// Factors given in the options, with the response to the challenge of
+/// `file` if it has one.
+fn factors_for(file: &File) -> guarakapa::Result<Factors> {
+    match file.challenge() {
+        Some(challenge) => Ok(factors().clone().with_response(&respond(challenge)?)),
+        None => Ok(factors().clone()),
+    }
+}
+
+/// Response of the `challenge.command` setting to `challenge`. The reason
+/// why there is none is shown to the user.
+fn respond(challenge: &[u8]) -> guarakapa::Result<SecretBytes> {
+    let command = match CHALLENGE_COMMAND.get() {
+        Some(command) => command,
+        None => {
+            println!("Set `challenge.command` to the command giving the response.");
+            return Err(guarakapa::Error::ResponseRequired);
+        }
+    };
+
+    challenge::respond(command, challenge).map_err(|e| {
+        println!("{:#}", e);
+        guarakapa::Error::ResponseRequired
+    })
+}
+
+/// Unlocks a team vault with the keypair kept in the personal vault.
+fn unlock_team_file(path: &Path, file: File) -> guarakapa::Result<UnlockedVault> {
+    let keypair = match PERSONAL_VAULT_PATH.get() {
+        Some(personal_path) if personal_path != path => {
+            let prompt = "Enter the master password of your personal vault: ";
+            unlock_file_with_prompt(personal_path, prompt)?.keypair()?
+        }
+        _ => None,
+    };
+
+    match keypair {
+        Some(keypair) => file.unlock_with_keypair(&keypair),
+        None => Err(guarakapa::Error::NotAMember),
+    }
+}
+
+fn request_agent(request: &agent::Request) -> anyhow::Result<agent::Response> {
+    agent::request(&agent::socket_path()?, request)
+}
+
+/// Key of the data file kept by the agent, if the agent is running and the
+/// file was unlocked in it.
+fn key_from_agent(contents: Vec<u8>) -> Option<SecretBytes> {
+    match request_agent(&agent::Request::Open { file: contents }) {
+        Ok(agent::Response::Key(key)) => Some(key),
+        _ => None,
+    }
+}
+
+fn unlock_agent(path: &Path) {
+    match agent::socket_path() {
+        Ok(socket) if socket.exists() => (),
+        _ => {
+            println!("The agent is not running (start it with `kapa-agent`).");
+            return;
+        }
+    }
+
+    let contents = fs::load_from(path).expect(MSG_LOAD_ERR);
+    if let Ok(file) = codec::decode::<File>(contents.as_slice()) {
+        if file.challenge().is_some() {
+            println!("The key of a vault with a challenge changes on every save, so the agent cannot keep it.");
+            return;
+        }
+    }
+
+    let pw = get_input_pw(MSG_ENTER_PW);
+    eprintln!();
+
+    let request = agent::Request::Unlock {
+        file: contents,
+        pw,
+        factors: factors().clone(),
+    };
+    match request_agent(&request) {
+        Ok(agent::Response::Unlocked { timeout }) => println!(
+            "Data file unlocked in the agent until it is not used for {} seconds.",
+            timeout
+        ),
+        Ok(agent::Response::Error(e)) => println!("Could not unlock data file. Reason: {}", e),
+        Ok(_) => println!("Unexpected response from the agent."),
+        Err(e) => println!("Could not reach the agent. Reason: {}", e),
+    }
+}
+
+/// Creates a random key file at `key_file`, which the vault then requires
+/// to be unlocked along with the master password.
+fn create_key_file(path: &Path, config: &Config, key_file: &str) {
+    if Path::new(key_file).exists() {
+        println!("There is a file at `{}` already.", key_file);
+        return;
+    }
+
+    let contents = fs::load_from(path).expect(MSG_LOAD_ERR);
+    let file: File = match codec::decode(contents.as_slice()) {
+        Ok(file) => file,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
+    if file.is_team() {
+        println!("{}", guarakapa::Error::TeamVault);
+        return;
+    }
+    if file.requires_key_file() && factors().key_file.is_none() {
+        println!("{}", guarakapa::Error::KeyFileRequired);
+        return;
+    }
+
+    let pw = get_input_pw(MSG_ENTER_PW);
+    eprintln!();
+    let factors = match factors_for(&file) {
+        Ok(factors) => factors,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+    let mut vault = match file.unlock_with_factors(&pw, &factors) {
+        Ok(vault) => vault,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+    if read_only() {
+        vault.set_read_only();
+    }
+
+    let key: String = crypto::generate_bytes(KEY_FILE_LEN)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .chain(std::iter::once(String::from("\n")))
+        .collect();
+    let new_factors = Factors {
+        response: factors.response,
+        ..Factors::with_key_file(key.as_bytes())
+    };
+
+    // the key file is only written once the vault accepts the change, and
+    // the vault only once the key file is written
+    if let Err(e) = vault.change_credentials(&pw, config.kdf.kdf(), &new_factors) {
+        println!("Could not change the vault key. Reason: {}", e);
+        return;
+    }
+    if let Err(e) = fs::save_private(key_file, key.as_bytes()) {
+        println!("Could not create the key file. Reason: {}", e);
+        return;
+    }
+
+    save_vault(path, config, &mut vault);
+    println!(
+        "Key file created (at {}). The vault can no longer be unlocked without it \
+         (`--keyfile`): keep a copy in a safe place.",
+        key_file
+    );
+}
+
+fn lock_agent() {
+    match request_agent(&agent::Request::Lock) {
+        Ok(agent::Response::Locked) => println!("Agent locked."),
+        Ok(_) => println!("Unexpected response from the agent."),
+        Err(_) => println!("The agent is not running."),
+    }
+}
+
+/// Sets a new challenge, for vaults that have one, and saves the vault.
+fn save_vault(path: &Path, config: &Config, vault: &mut UnlockedVault) {
+    if vault.challenge().is_some() {
+        if let Err(e) = set_new_challenge(vault) {
+            println!(
+                "Could not change the challenge (the old one is kept). Reason: {}",
+                e
+            );
+        }
+    }
+
+    write_vault(path, config, vault);
+}
+
+fn write_vault(path: &Path, config: &Config, vault: &mut UnlockedVault) {
+    let file = vault.seal().expect(MSG_ENCODE_ERR);
+    fs::backup(path, config.backup.keep).expect(MSG_SAVE_ERR);
+    fs::save_atomically(path, codec::encode(file).expect(MSG_ENCODE_ERR)).expect(MSG_SAVE_ERR);
+
+    if config.sync.remote.is_some() {
+        if let Err(e) = sync::Repo::new(path).and_then(|repo| repo.commit(&config.sync.branch)) {
+            println!("Could not commit the data file. Reason: {:#}", e);
+        }
+    }
+}
+
+fn set_new_challenge(vault: &mut UnlockedVault) -> guarakapa::Result<()> {
+    let challenge = fman::new_challenge();
+    let response = respond(&challenge)?;
+    vault.set_challenge(challenge, &response)
+}
+
+/// Makes the vault require the response of the `challenge.command` setting
+/// to a challenge (with `enable`), or stops requiring it.
+fn change_challenge(path: &Path, config: &Config, enable: bool) {
+    let mut vault = match unlock_file(path) {
+        Ok(vault) => vault,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
+    if vault.challenge().is_some() == enable {
+        let state = if enable { "already" } else { "not" };
+        println!("The vault {} requires a response to a challenge.", state);
+        return;
+    }
+
+    let result = if enable {
+        set_new_challenge(&mut vault)
+    } else {
+        vault.remove_challenge()
+    };
+
+    match result {
+        Ok(()) => {
+            write_vault(path, config, &mut vault);
+            if enable {
+                println!(
+                    "Challenge set. The vault can no longer be unlocked without its response."
+                );
+            } else {
+                println!("Challenge removed.");
+            }
+        }
+        Err(e) => println!("Could not change the vault key. Reason: {}", e),
+    }
+}
+
+/// Attaches the file at `file` to the entry `name`, under its file name.
+fn attach_file(path: &Path, config: &Config, name: &str, file: &str) {
+    let attachment = match Path::new(file).file_name() {
+        Some(attachment) => attachment.to_string_lossy().to_string(),
+        None => {
+            println!("Invalid file `{}`.", file);
+            return;
+        }
+    };
+
+    // checked before asking for the password
+    match std::fs::metadata(file) {
+        Ok(metadata) if metadata.len() > fman::MAX_ATTACHMENT_LEN as u64 => {
+            let e = guarakapa::Error::AttachmentTooLarge {
+                name: attachment,
+                limit: fman::MAX_ATTACHMENT_LEN,
+            };
+            println!("{}", e);
+            return;
+        }
+        Ok(_) => (),
+        Err(e) => {
+            println!("Could not read `{}`. Reason: {}", file, e);
+            return;
+        }
+    }
+    let mut data = match std::fs::read(file) {
+        Ok(data) => data,
+        Err(e) => {
+            println!("Could not read `{}`. Reason: {}", file, e);
+            return;
+        }
+    };
+
+    let mut vault = match unlock_file(path) {
+        Ok(vault) => vault,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
+    let result = vault.attach(name, attachment.clone(), &data);
+    data.zeroize();
+
+    match result {
+        Ok(()) => {
+            save_vault(path, config, &mut vault);
+            println!("File `{}` attached to entry `{}`.", attachment, name);
+        }
+        Err(e) => println!("{}", e),
+    }
+}
+
+/// Lists the files attached to the entry `name`.
+fn list_attachments(path: &Path, name: &str) {
+    let vault = match unlock_file(path) {
+        Ok(vault) => vault,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
+    match vault.attachments(name) {
+        Ok(attachments) => {
+            for info in attachments {
+                println!(
+                    "  {}  {} bytes  added {}",
+                    info.name,
+                    info.size,
+                    time::format(info.added)
+                );
+            }
+        }
+        Err(e) => println!("{}", e),
+    }
+}
+
+/// Writes the attachment `attachment` of the entry `name` to a new file at
+/// `out`, readable only by the user.
+fn extract_attachment(path: &Path, name: &str, attachment: &str, out: &str) {
+    if Path::new(out).exists() {
+        println!("There is a file at `{}` already.", out);
+        return;
+    }
+
+    let vault = match unlock_file(path) {
+        Ok(vault) => vault,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
+    match vault.extract(name, attachment) {
+        Ok(data) => match fs::save_private(out, &data) {
+            Ok(()) => println!("Attachment `{}` written to `{}`.", attachment, out),
+            Err(e) => println!("Could not write `{}`. Reason: {}", out, e),
+        },
+        Err(e) => println!("{}", e),
+    }
+}
+
+fn detach_file(path: &Path, config: &Config, name: &str, attachment: &str) {
+    let mut vault = match unlock_file(path) {
+        Ok(vault) => vault,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
+    match vault.detach(name, attachment) {
+        Ok(()) => {
+            save_vault(path, config, &mut vault);
+            println!("Attachment `{}` removed from entry `{}`.", attachment, name);
+        }
+        Err(e) => println!("{}", e),
+    }
+}
+
+/// Shows how much the data file tells about the entries of the vault.
+fn show_padding(path: &Path) {
+    match unlock_file(path) {
+        Ok(vault) => println!("{}", vault.padding()),
+        Err(e) => println!("{}", e),
+    }
+}
+
+fn change_padding(path: &Path, config: &Config, padding: Padding) {
+    let mut vault = match unlock_file(path) {
+        Ok(vault) => vault,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
+    if vault.padding() == padding {
+        println!("The padding of the vault is already `{}`.", padding);
+        return;
+    }
+
+    match vault.set_padding(padding) {
+        Ok(()) => {
+            save_vault(path, config, &mut vault);
+            println!("Padding set to `{}`.", padding);
+        }
+        Err(e) => println!("Could not change the padding. Reason: {}", e),
+    }
+}
+
+/// Adds a recovery key slot to the vault, showing the new recovery key.
+fn create_recovery_key(path: &Path, config: &Config) {
+    let mut vault = match unlock_file(path) {
+        Ok(vault) => vault,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
+    match vault.add_recovery_slot() {
+        Ok(recovery_key) => {
+            save_vault(path, config, &mut vault);
+            let slot = vault.recovery_slots().last().expect(MSG_LOAD_ERR);
+            println!(
+                "Recovery key (slot {}):\n\n    {}\n\n\
+                 Write it down and keep it in a safe place: it opens the vault without \
+                 the master password (`recovery use`).",
+                slot.id(),
+                &*recovery_key.encode()
+            );
+        }
+        Err(e) => println!("Could not create the recovery key. Reason: {}", e),
+    }
+}
+
+/// Lists the recovery key slots of the vault. They are kept unencrypted in
+/// the data file, so it does not have to be unlocked.
+fn list_recovery_slots(path: &Path) {
+    let contents = fs::load_from(path).expect(MSG_LOAD_ERR);
+    let file: File = match codec::decode(contents.as_slice()) {
+        Ok(file) => file,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
+    for slot in file.recovery_slots() {
+        println!("  {}  created {}", slot.id(), time::format(slot.created));
+    }
+}
+
+fn revoke_recovery_slot(path: &Path, config: &Config, id: &str) {
+    let mut vault = match unlock_file(path) {
+        Ok(vault) => vault,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
+    match vault.revoke_recovery_slot(id) {
+        Ok(()) => {
+            save_vault(path, config, &mut vault);
+            println!("Recovery key slot `{}` revoked.", id);
+        }
+        Err(e) => println!("{}", e),
+    }
+}
+
+fn use_recovery_key(path: &Path, config: &Config) {
+    let text = get_input_pw("Enter the recovery key: ");
+    eprintln!();
+
+    match RecoveryKey::parse(&text) {
+        Ok(recovery_key) => reset_master_password(path, config, &recovery_key),
+        Err(e) => println!("{}", e),
+    }
+}
+
+/// Unlocks the vault with a recovery key, and sets a new master password.
+/// The key file and challenge, if any, are no longer required.
+fn reset_master_password(path: &Path, config: &Config, recovery_key: &RecoveryKey) {
+    let contents = fs::load_from(path).expect(MSG_LOAD_ERR);
+    let mut vault = match codec::decode::<File>(contents.as_slice())
+        .and_then(|file| file.unlock_with_recovery_key(recovery_key))
+    {
+        Ok(vault) => vault,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+    if read_only() {
+        vault.set_read_only();
+    }
+
+    let pw = get_input_pw("Enter a new master password: ");
+    println!();
+    let confirm = get_input_pw("Please repeat: ");
+    println!();
+
+    if pw != confirm {
+        println!("Password confirmation incorrect!");
+        return;
+    }
+
+    match vault.reset_credentials(&pw, config.kdf.kdf()) {
+        Ok(()) => {
+            save_vault(path, config, &mut vault);
+            println!("Master password changed.");
+        }
+        Err(e) => println!("Could not change the master password. Reason: {}", e),
+    }
+}
+
+/// Creates a recovery key and shows it split into `count` shares, any
+/// `threshold` of which open the vault (see `escrow`).
+fn split_recovery_key(path: &Path, config: &Config, threshold: u8, count: u8) {
+    let mut vault = match unlock_file(path) {
+        Ok(vault) => vault,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
+    let recovery_key = match vault.add_recovery_slot() {
+        Ok(recovery_key) => recovery_key,
+        Err(e) => {
+            println!("Could not create the recovery key. Reason: {}", e);
+            return;
+        }
+    };
+    let shares = match escrow::split(recovery_key.secret(), threshold, count) {
+        Ok(shares) => shares,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
+    save_vault(path, config, &mut vault);
+    let slot = vault.recovery_slots().last().expect(MSG_LOAD_ERR);
+    println!(
+        "Recovery key slot {} created, and its key split into {} shares. Any {} of them \
+         open the vault (`escrow combine`), but fewer reveal nothing about it. Hand \
+         each share to a different person:",
+        slot.id(),
+        count,
+        threshold
+    );
+
+    for share in shares {
+        println!("\nShare {}:\n    {}", share.id(), &*share.encode());
+    }
+}
+
+/// Rebuilds a recovery key from the shares typed by the user, and sets a new
+/// master password with it.
+fn combine_shares(path: &Path, config: &Config) {
+    let mut shares: Vec<Share> = Vec::new();
+
+    while shares.len() < shares.first().map_or(2, |share| share.threshold as usize) {
+        let text = get_input_pw(&format!("Enter share {}: ", shares.len() + 1));
+        eprintln!();
+
+        match Share::parse(&text) {
+            Ok(share) => shares.push(share),
+            Err(e) => {
+                println!("{}", e);
+                return;
+            }
+        }
+    }
+
+    let recovery_key = match escrow::combine(&shares) {
+        Ok(secret) => RecoveryKey::from_secret(secret),
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
+    match recovery_key {
+        Ok(recovery_key) => reset_master_password(path, config, &recovery_key),
+        Err(e) => println!("{}", e),
+    }
+}
+
+fn parse_split_opts(opts: &[&str]) -> Option<(u8, u8)> {
+    let mut threshold = None;
+    let mut count = None;
+    let mut opts = opts.iter();
+
+    while let Some(opt) = opts.next() {
+        match *opt {
+            "-k" => threshold = Some(opts.next()?.parse().ok()?),
+            "-n" => count = Some(opts.next()?.parse().ok()?),
+            _ => return None,
+        }
+    }
+
+    Some((threshold?, count?))
+}
+
+/// Unlocks `contents`, another version of the vault `vault`, with its key,
+/// or else with a master password typed by the user.
+fn unlock_version(
+    vault: &UnlockedVault,
+    contents: &[u8],
+    prompt: &str,
+) -> guarakapa::Result<UnlockedVault> {
+    match vault.unlock_other(codec::decode(contents)?) {
+        Err(guarakapa::Error::WrongPassword) => {
+            unlock_with_password(codec::decode(contents)?, prompt)
+        }
+        result => result,
+    }
+}
+
+/// Asks the user which version of a conflicting entry to keep.
+fn resolve_conflict(conflict: &Conflict) -> Side {
+    println!("\nEntry `{}` was changed on both sides.", conflict.name);
+
+    for (label, entry) in [("Ours", conflict.ours), ("Theirs", conflict.theirs)] {
+        match entry {
+            Some(entry) => println!(
+                "{} (modified {}):\n{}",
+                label,
+                time::format(entry.modified),
+                entry
+            ),
+            None => println!("{}: removed", label),
+        }
+    }
+
+    loop {
+        println!("Keep [o]urs or [t]heirs?");
+
+        match get_input().as_str() {
+            "o" | "ours" => return Side::Ours,
+            "t" | "theirs" => return Side::Theirs,
+            _ => (),
+        }
+    }
+}
+
+/// Shows the public key of the user, to be given to the owners of team
+/// vaults. The keypair is generated (as RSA if `rsa` is set, otherwise
+/// X25519) and kept in the personal vault on first use.
+fn show_public_key(config: &Config, rsa: bool, read_only: bool) {
+    let path = PERSONAL_VAULT_PATH.get().expect(MSG_LOAD_ERR);
+    if !path.exists() {
+        println!("Create your personal vault first (run `kapa`).");
+        return;
+    }
+
+    let mut vault = match unlock_file(path) {
+        Ok(vault) => vault,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
+    let keypair = match vault.keypair() {
+        Ok(Some(keypair)) => keypair,
+        Ok(None) if read_only => {
+            println!("You have no keypair yet.\n{}", MSG_READ_ONLY);
+            return;
+        }
+        Ok(None) => {
+            let keypair = if rsa {
+                Keypair::generate_rsa()
+            } else {
+                Keypair::generate_x25519()
+            };
+            match keypair.and_then(|keypair| vault.set_keypair(&keypair).map(|_| keypair)) {
+                Ok(keypair) => {
+                    save_vault(path, config, &mut vault);
+                    eprintln!("A new keypair was created and kept in your personal vault.");
+                    keypair
+                }
+                Err(e) => {
+                    println!("Could not create keypair. Reason: {}", e);
+                    return;
+                }
+            }
+        }
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
+    match keypair
+        .public_key()
+        .and_then(|key| crypto::public_key_to_pem(&key))
+    {
+        Ok(pem) => print!("{}", pem),
+        Err(e) => println!("{}", e),
+    }
+}
+
+/// Lists the members of a team vault. Members are kept unencrypted in the
+/// data file, so it does not have to be unlocked.
+fn list_members(path: &Path) {
+    let contents = fs::load_from(path).expect(MSG_LOAD_ERR);
+    let file: File = match codec::decode(contents.as_slice()) {
+        Ok(file) => file,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
+    if !file.is_team() {
+        println!("{}", guarakapa::Error::NotATeamVault);
+        return;
+    }
+
+    let width = file
+        .members()
+        .iter()
+        .map(|member| member.name.chars().count())
+        .max()
+        .unwrap_or(0);
+
+    for member in file.members() {
+        println!(
+            "  {:width$}  {}",
+            member.name,
+            member.fingerprint(),
+            width = width
+        );
+    }
+}
+
+/// Adds a member to a team vault, with the public key (PEM) in `key_file`.
+/// Without it, the data file is created as a team vault whose first member
+/// is the user, with their own public key.
+fn add_member(path: &Path, config: &Config, name: &str, key_file: Option<&str>) {
+    let key_file = match (path.exists(), key_file) {
+        (true, Some(key_file)) => key_file,
+        (true, None) => {
+            println!("Give the file with the public key of `{}`.", name);
+            return;
+        }
+        (false, Some(_)) => {
+            println!("Create the team vault by adding yourself first (`member add YOUR_NAME`).");
+            return;
+        }
+        (false, None) => {
+            create_team_vault(path, config, name);
+            return;
+        }
+    };
+
+    let public_key = match std::fs::read(key_file) {
+        Ok(pem) => crypto::public_key_from_pem(&pem),
+        Err(e) => Err(e.into()),
+    };
+    let public_key = match public_key {
+        Ok(public_key) => public_key,
+        Err(e) => {
+            println!("Could not read the public key. Reason: {}", e);
+            return;
+        }
+    };
+
+    let mut vault = match unlock_file(path) {
+        Ok(vault) => vault,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
+    match vault.add_member(name.to_string(), public_key) {
+        Ok(()) => {
+            save_vault(path, config, &mut vault);
+            println!("Member `{}` added.", name);
+        }
+        Err(e) => println!("Could not add member. Reason: {}", e),
+    }
+}
+
+/// Creates a team vault whose first member is the user, as `name`.
+fn create_team_vault(path: &Path, config: &Config, name: &str) {
+    if read_only() {
+        println!("Team vault not found.\n{}", MSG_READ_ONLY);
+        return;
+    }
+    let personal_path = PERSONAL_VAULT_PATH.get().expect(MSG_LOAD_ERR);
+    if personal_path == path {
+        println!("Your personal vault cannot be a team vault (select one with `--vault`).");
+        return;
+    }
+    if !personal_path.exists() {
+        println!("Create your personal vault first (run `kapa`).");
+        return;
+    }
+
+    let prompt = "Enter the master password of your personal vault: ";
+    let keypair = match unlock_file_with_prompt(personal_path, prompt).and_then(|v| v.keypair()) {
+        Ok(Some(keypair)) => keypair,
+        Ok(None) => {
+            println!("You have no keypair yet (create it with `member key`).");
+            return;
+        }
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
+    match keypair
+        .public_key()
+        .and_then(|public_key| File::try_new_team(name.to_string(), public_key))
+    {
+        Ok(mut vault) => {
+            save_vault(path, config, &mut vault);
+            println!("Team vault created (at {}).", path.display());
+        }
+        Err(e) => println!("Could not create team vault. Reason: {}", e),
+    }
+}
+
+/// Removes a member from a team vault, which gets a new key.
+fn remove_member(path: &Path, config: &Config, name: &str) {
+    let mut vault = match unlock_file(path) {
+        Ok(vault) => vault,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
+    match vault.remove_member(name) {
+        Ok(()) => {
+            save_vault(path, config, &mut vault);
+            println!("Member `{}` removed, and the vault key changed.", name);
+        }
+        Err(e) => println!("Could not remove member. Reason: {}", e),
+    }
+}
+
+/// Lists the groups of a team vault with their members. Groups are kept
+/// unencrypted in the data file (their entries are not), so it does not have
+/// to be unlocked.
+fn list_groups(path: &Path) {
+    let contents = fs::load_from(path).expect(MSG_LOAD_ERR);
+    let file: File = match codec::decode(contents.as_slice()) {
+        Ok(file) => file,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
+    let width = file
+        .groups()
+        .iter()
+        .map(|group| group.name.chars().count())
+        .max()
+        .unwrap_or(0);
+
+    for group in file.groups() {
+        println!(
+            "  {:width$}  {}",
+            group.name,
+            group.members().join(", "),
+            width = width
+        );
+    }
+}
+
+/// Creates a group in a team vault, whose only member is the user.
+fn create_group(path: &Path, config: &Config, name: &str) {
+    let mut vault = match unlock_file(path) {
+        Ok(vault) => vault,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
+    match vault.create_group(name.to_string()) {
+        Ok(()) => {
+            save_vault(path, config, &mut vault);
+            println!("Group `{}` created.", name);
+        }
+        Err(e) => println!("Could not create group. Reason: {}", e),
+    }
+}
+
+/// Adds a member of a team vault to one of its groups (with `add`), or
+/// removes them from it, which gets a new key.
+fn change_group(path: &Path, config: &Config, group: &str, member: &str, add: bool) {
+    let mut vault = match unlock_file(path) {
+        Ok(vault) => vault,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
+    if !add
+        && vault
+            .groups()
+            .iter()
+            .any(|g| g.name == group && g.members() == [member])
+    {
+        println!("The last member of a group cannot be removed.");
+        return;
+    }
+
+    let result = match add {
+        true => vault.add_to_group(group, member),
+        false => vault.remove_from_group(group, member),
+    };
+
+    match (result, add) {
+        (Ok(()), true) => {
+            save_vault(path, config, &mut vault);
+            println!("Member `{}` added to group `{}`.", member, group);
+        }
+        (Ok(()), false) => {
+            save_vault(path, config, &mut vault);
+            println!(
+                "Member `{}` removed from group `{}`, and the group key changed.",
+                member, group
+            );
+        }
+        (Err(e), _) => println!("Could not change group. Reason: {}", e),
+    }
+}
+
+/// Merges the changes made in the data file `other` (another copy of the
+/// vault) into the data file, given the copy `base` they both came from.
+/// Entries changed on both sides in different ways are resolved by the
+/// user. The master password of the data file is kept.
+fn merge_file(path: &Path, config: &Config, other: &str, base: Option<&str>) {
+    let mut vault = match unlock_file(path) {
+        Ok(vault) => vault,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
+    let unlock = |file: &str| -> anyhow::Result<UnlockedVault> {
+        let contents = fs::load_from(file)?;
+        let prompt = format!("Enter the master password of `{}`: ", file);
+        Ok(unlock_version(&vault, &contents, &prompt)?)
+    };
+
+    let (theirs, base) = match (unlock(other), base.map(unlock).transpose()) {
+        (Ok(theirs), Ok(base)) => (theirs, base),
+        (Err(e), _) | (_, Err(e)) => {
+            println!("Could not open the data file to merge. Reason: {:#}", e);
+            return;
+        }
+    };
+
+    match merge::merge(&mut vault, base.as_ref(), &theirs, resolve_conflict) {
+        Ok(summary) if summary.is_empty() => println!("Nothing to merge."),
+        Ok(summary) => {
+            save_vault(path, config, &mut vault);
+            println!("Merged `{}`: {}.", other, summary);
+        }
+        Err(e) => println!("Could not merge. Reason: {}", e),
+    }
+}
+
+fn parse_diff_opts(opts: &[&str]) -> Option<(bool, bool)> {
+    let mut show_secrets = false;
+    let mut json = false;
+
+    for opt in opts {
+        match *opt {
+            "--show-secrets" => show_secrets = true,
+            "--json" => json = true,
+            _ => return None,
+        }
+    }
+
+    Some((show_secrets, json))
+}
+
+/// Shows the entries added, removed, renamed and modified in the data file
+/// `new` with respect to `old`. Passwords are masked unless `show_secrets`
+/// is set.
+fn diff_files(old: &str, new: &str, show_secrets: bool, json: bool) {
+    if let Some(file) = [old, new].into_iter().find(|f| !Path::new(f).exists()) {
+        println!("File `{}` not found.", file);
+        return;
+    }
+
+    let prompt = format!("Enter the master password of `{}`: ", old);
+    let entries = unlock_file_with_prompt(Path::new(old), &prompt).and_then(|old_vault| {
+        let contents = fs::load_from(new)?;
+        let prompt = format!("Enter the master password of `{}`: ", new);
+        let new_vault = unlock_version(&old_vault, &contents, &prompt)?;
+        Ok((old_vault.get_all()?, new_vault.get_all()?))
+    });
+    let (old_entries, new_entries) = match entries {
+        Ok(entries) => entries,
+        Err(e) => {
+            println!("Error retrieving entries: {}", e);
+            return;
+        }
+    };
+
+    let changes = diff::diff(&old_entries, &new_entries, show_secrets);
+
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&changes).expect(MSG_ENCODE_ERR)
+        );
+        return;
+    }
+
+    if changes.is_empty() {
+        println!("No differences.");
+        return;
+    }
 
-    stdout.write_all(prompt.as_bytes()).unwrap();
-    stdout.flush().unwrap();
+    let show = |value: Option<String>| match value {
+        Some(value) => format!("{:?}", value),
+        None => String::from("******"),
+    };
 
-    let pass = stdin.read_passwd(&mut stdout);
+    for change in changes {
+        match change {
+            Change::Added { name } => println!("  added     {}", name),
+            Change::Removed { name } => println!("  removed   {}", name),
+            Change::Renamed { from, to } => println!("  renamed   {} -> {}", from, to),
+            Change::Modified { name, fields } => {
+                println!("  modified  {}", name);
 
-    if let Ok(Some(pass)) = pass {
-        return pass;
-    } else {
-        panic!("Failed to read input!");
+                for field in fields {
+                    println!(
+                        "      {}: {} -> {}",
+                        field.field,
+                        show(field.old),
+                        show(field.new)
+                    );
+                }
+            }
+        }
     }
 }
 
-fn get_input() -> String {
-    let mut s = String::new();
-    std::io::stdin().read_line(&mut s).unwrap();
-    s.trim_end().to_owned()
-}
+/// Commits the data file and pulls and pushes the changes to the remote
+/// repository, merging them with the local changes if needed.
+fn sync_vault(path: &Path, config: &Config) {
+    // pulling writes the data file without unlocking it
+    if read_only() {
+        println!("{}", MSG_READ_ONLY);
+        return;
+    }
+    let remote = match &config.sync.remote {
+        Some(remote) => remote,
+        None => {
+            println!("No remote configured (set `sync.remote`).");
+            return;
+        }
+    };
 
-/// When this function returns, clipboard is dropped and its contents too
-fn copy_to_clipboard_and_block(text: String) {
-    let clipboard = x11_clipboard::Clipboard::new().unwrap();
-    clipboard
-        .store(
-            clipboard.setter.atoms.clipboard,
-            clipboard.setter.atoms.utf8_string,
-            text,
-        )
-        .unwrap();
+    let merge_versions = |base: Option<Vec<u8>>, theirs: Vec<u8>| -> anyhow::Result<Vec<u8>> {
+        let mut vault = unlock_file(path)?;
+        let prompt = "Enter the master password of the remote data file: ";
+        let theirs = unlock_version(&vault, &theirs, prompt)?;
+        let base = match base {
+            Some(base) => {
+                let prompt = "Enter the master password of the last synced data file: ";
+                Some(unlock_version(&vault, &base, prompt)?)
+            }
+            None => None,
+        };
 
-    get_input();
-}
+        let summary = merge::merge(&mut vault, base.as_ref(), &theirs, resolve_conflict)?;
+        println!("Merged the remote changes: {}.", summary);
+        Ok(codec::encode(vault.seal()?)?)
+    };
 
-fn get_input_field(fieldname: &str) -> String {
-    println!(
-        "Enter {} for this entry (or just press ENTER to leave it blank):",
-        fieldname
-    );
+    let status = sync::Repo::new(path)
+        .and_then(|repo| repo.sync(remote, &config.sync.branch, merge_versions));
 
-    get_input()
+    match status {
+        Ok(sync::Status::UpToDate) => println!("Already up to date."),
+        Ok(sync::Status::Pushed) => println!("Local changes pushed."),
+        Ok(sync::Status::Pulled) => println!("Remote changes pulled."),
+        Ok(sync::Status::Merged) => println!("Local and remote changes merged and pushed."),
+        Err(e) => println!("Could not sync. Reason: {:#}", e),
+    }
 }
 
-fn create_new_file() {
-    let pw = get_input_pw("Enter a new master password: ");
-    println!();
-    let confirm = get_input_pw("Please repeat: ");
-    println!();
-
-    if pw != confirm {
-        println!("Password confirmation incorrect!");
-    } else {
-        let file = File::try_new(pw).expect("Error creating new file.");
-        fs::save(codec::encode(&file).expect(MSG_ENCODE_ERR)).expect(MSG_SAVE_ERR);
+fn parse_add_opts<'a>(opts: &[&'a str]) -> Option<(bool, Option<&'a str>)> {
+    let mut generate = false;
+    let mut group = None;
+    let mut opts = opts.iter();
 
-        println!(
-            "Your password file was created (at {}). \
-             Run the program again to add new entries.",
-            fs::file_path()
-        );
+    while let Some(opt) = opts.next() {
+        match *opt {
+            "-g" | "--generate" => generate = true,
+            "--group" => group = Some(*opts.next()?),
+            _ => return None,
+        }
     }
-}
 
-fn add_entry(entry_name: &str) {
-    let contents = fs::load().expect(MSG_LOAD_ERR);
-    let mut file: File = codec::decode(contents.as_slice()).expect(MSG_DECODE_ERR);
-
-    let pw = get_input_pw(MSG_ENTER_PW);
-    println!();
+    Some((generate, group))
+}
 
-    let pw_hash = crypto::hash(vec![pw.as_bytes(), &file.head.salt[..]]);
+/// Adds an entry, in the group `group` of a team vault if given. With
+/// `generate`, its password is random.
+fn add_entry(path: &Path, config: &Config, entry_name: &str, generate: bool, group: Option<&str>) {
+    let mut vault = match unlock_file(path) {
+        Ok(vault) => vault,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
 
-    if pw_hash != file.head.pw_hash {
-        println!("{}", MSG_WRONG_PW);
+    // before asking for the fields of an entry that cannot be added
+    if vault.is_read_only() {
+        println!("{}", guarakapa::Error::ReadOnly);
         return;
     }
 
-    let entry = fman::OpenEntry {
-        desc: get_input_field("a description"),
-        user: get_input_field("a user name"),
-        email: get_input_field("an email"),
-        notes: get_input_field("other notes/observations"),
-        pw: get_input_pw("Enter a new password for this entry: "),
-    };
+    let mut entry = fman::OpenEntry::default();
 
-    println!();
+    for field in &config.add.fields {
+        match field {
+            Field::Description => entry.desc = get_input_field("a description"),
+            Field::User => entry.user = get_input_field("a user name"),
+            Field::Email => entry.email = get_input_field("an email"),
+            Field::Notes => entry.notes = get_input_field("other notes/observations"),
+        }
+    }
 
-    if let Err(e) = file.add_entry(pw, entry_name.to_string(), entry) {
+    if generate {
+        match generator::generate(&config.generator) {
+            Ok(pw) => entry.pw = pw,
+            Err(e) => {
+                println!("Could not generate password. Reason: {}", e);
+                return;
+            }
+        }
+        println!("A random password was generated for this entry.");
+    } else {
+        entry.pw = get_input_pw("Enter a new password for this entry: ");
+        println!();
+    }
+
+    if let Err(e) = vault.add_in_group(entry_name.to_string(), entry, group) {
         println!("Could not add entry. Reason: {}", e);
     } else {
-        fs::save(codec::encode(&file).expect(MSG_ENCODE_ERR)).expect(MSG_SAVE_ERR);
+        save_vault(path, config, &mut vault);
         println!("Entry '{}' added successfully.", entry_name);
     }
 }
 
-fn get_entry(entry_name: &str) {
-    let contents = fs::load().expect(MSG_LOAD_ERR);
-    let mut file: File = codec::decode(contents.as_slice()).expect(MSG_DECODE_ERR);
-
-    let pw = get_input_pw(MSG_ENTER_PW);
-    println!();
+/// Shows an entry and copies its password. With `track_usage` set, and
+/// unless the vault is read-only, the time it was used is recorded in the
+/// data file.
+fn get_entry(path: &Path, config: &Config, entry_name: &str) {
+    let mut vault = match unlock_file(path) {
+        Ok(vault) => vault,
+        Err(e) => {
+            println!("Error retrieving entry. Reason: {}", e);
+            return;
+        }
+    };
 
-    match file.get_entry(pw, entry_name) {
+    match vault.get(entry_name) {
         Err(e) => println!("Error retrieving entry. Reason: {}", e),
         Ok(Some(entry)) => {
+            if config.track_usage && vault.mark_used(entry_name).is_ok() {
+                save_vault(path, config, &mut vault);
+            }
+
+            let clipboard = &config.clipboard;
+
+            if clipboard.backend == ClipboardBackend::None {
+                println!(
+                    "\nEntry `{}` retrieved.\n{}\
+        Password: ******   [not copied, the clipboard is disabled].",
+                    entry_name, entry
+                );
+                return;
+            }
+
             println!(
                 "\nEntry `{}` retrieved.\n{}\
         Password: ******   [copied to clipboard, paste to use].\n\n\
         Press ENTER to close the program (clipboard may be erased).",
                 entry_name, entry
             );
-            copy_to_clipboard_and_block(entry.pw);
+            if clipboard.timeout > 0 {
+                println!(
+                    "The clipboard is erased after {} seconds.",
+                    clipboard.timeout
+                );
+            }
+
+            if let Err(e) = copy_to_clipboard_and_block(&entry.pw, clipboard) {
+                println!("Could not copy password. Reason: {}", e);
+            }
         }
         _ => println!("Entry `{}` not found.", entry_name),
     }
 }
 
-fn remove_entry(entry_name: &str) {
-    let contents = fs::load().expect(MSG_LOAD_ERR);
-    let mut file: File = codec::decode(contents.as_slice()).expect(MSG_DECODE_ERR);
-
-    let pw = get_input_pw(MSG_ENTER_PW);
-    println!();
-
-    let pw_hash = crypto::hash(vec![pw.as_bytes(), &file.head.salt[..]]);
-
-    if pw_hash != file.head.pw_hash {
-        println!("{}", MSG_WRONG_PW);
-        return;
-    }
+fn remove_entry(path: &Path, config: &Config, entry_name: &str) {
+    let mut vault = match unlock_file(path) {
+        Ok(vault) => vault,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
 
-    if let Err(e) = file.remove_entry(pw, entry_name) {
+    if let Err(e) = vault.remove(entry_name) {
         println!("Could not remove entry. Reason: {}", e);
         return;
     }
 
-    fs::save(codec::encode(&file).expect(MSG_ENCODE_ERR)).expect(MSG_SAVE_ERR);
+    save_vault(path, config, &mut vault);
     println!("Entry '{}' removed successfully.", entry_name);
 }
 
-fn list_entries() {
-    let contents = fs::load().expect(MSG_LOAD_ERR);
-    let mut file: File = codec::decode(contents.as_slice()).expect(MSG_DECODE_ERR);
+fn parse_list_opts(opts: &[&str]) -> Option<(bool, SortKey, bool)> {
+    let mut long = false;
+    let mut sort = SortKey::Name;
+    let mut json = false;
+    let mut opts = opts.iter();
 
-    let pw = get_input_pw(MSG_ENTER_PW);
-    println!();
+    while let Some(opt) = opts.next() {
+        match *opt {
+            "-l" | "--long" => long = true,
+            "--sort" => sort = SortKey::parse(opts.next()?)?,
+            "--json" => json = true,
+            _ => return None,
+        }
+    }
+
+    Some((long, sort, json))
+}
+
+fn list_entries(path: &Path, long: bool, sort: SortKey, json: bool) {
+    if long || sort != SortKey::Name {
+        list_entries_with_times(path, long, sort, json);
+        return;
+    }
 
-    match file.list(pw) {
+    match unlock_file(path) {
         Err(e) => println!("Error retrieving entries: {}", e),
-        Ok(mut entries) => {
-            if !entries.is_empty() {
-                entries.sort();
+        Ok(vault) => {
+            let entries = vault.list();
+            if json {
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&entries).expect(MSG_ENCODE_ERR)
+                );
+            } else if !entries.is_empty() {
                 println!(
                     "Total entries ({}):\n  {}",
                     entries.len(),
@@ -183,8 +1603,374 @@ fn list_entries() {
     }
 }
 
-fn show_file_path() {
-    println!("data file path: {}", fs::file_path());
+/// Lists entries along with their timestamps. Unlike the plain listing, this
+/// needs to decrypt every entry.
+fn list_entries_with_times(path: &Path, long: bool, sort: SortKey, json: bool) {
+    let mut entries = match unlock_file(path).and_then(|vault| vault.get_all()) {
+        Err(e) => {
+            println!("Error retrieving entries: {}", e);
+            return;
+        }
+        Ok(entries) => entries,
+    };
+
+    entries.sort_by(|(name1, entry1), (name2, entry2)| {
+        sort.timestamp(entry2)
+            .cmp(&sort.timestamp(entry1))
+            .then_with(|| name1.cmp(name2))
+    });
+
+    if json {
+        let entries: Vec<serde_json::Value> = entries
+            .iter()
+            .map(|(name, entry)| match long {
+                true => serde_json::json!({
+                    "name": name,
+                    "created": entry.created,
+                    "modified": entry.modified,
+                    "pw_changed": entry.pw_changed,
+                    "last_used": entry.last_used,
+                }),
+                false => serde_json::json!(name),
+            })
+            .collect();
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&entries).expect(MSG_ENCODE_ERR)
+        );
+        return;
+    }
+
+    if entries.is_empty() {
+        println!("There are no entries yet.");
+        return;
+    }
+
+    println!("Total entries ({}):", entries.len());
+
+    if !long {
+        for (name, _) in entries {
+            println!("  {}", name);
+        }
+        return;
+    }
+
+    let width = entries
+        .iter()
+        .map(|(name, _)| name.chars().count())
+        .max()
+        .unwrap_or(0)
+        .max("NAME".len());
+
+    println!(
+        "  {:width$}  {:16}  {:16}  {:16}  {:16}",
+        "NAME",
+        "CREATED",
+        "MODIFIED",
+        "PW CHANGED",
+        "LAST USED",
+        width = width
+    );
+
+    for (name, entry) in entries {
+        println!(
+            "  {:width$}  {:16}  {:16}  {:16}  {:16}",
+            name,
+            time::format(entry.created),
+            time::format(entry.modified),
+            time::format(entry.pw_changed),
+            entry
+                .last_used
+                .map(time::format)
+                .unwrap_or_else(|| "never".to_string()),
+            width = width
+        );
+    }
+}
+
+fn parse_audit_opts(opts: &[&str]) -> Option<(audit::Options, bool)> {
+    let mut options = audit::Options::default();
+    let mut json = false;
+    let mut opts = opts.iter();
+
+    while let Some(opt) = opts.next() {
+        match *opt {
+            "--days" => options.max_age_days = opts.next()?.parse().ok()?,
+            "--months" => options.max_age_days = opts.next()?.parse::<u64>().ok()? * 30,
+            "--json" => json = true,
+            _ => return None,
+        }
+    }
+
+    Some((options, json))
+}
+
+fn describe_issue(issue: &audit::Issue, options: &audit::Options) -> String {
+    match issue {
+        audit::Issue::Empty => "password is empty".to_string(),
+        audit::Issue::Reused { with } => format!("password reused in: {}", with.join(", ")),
+        audit::Issue::Short { length } => format!(
+            "password too short ({} characters, minimum is {})",
+            length, options.min_length
+        ),
+        audit::Issue::Weak { entropy_bits } => {
+            format!("password easy to guess (~{:.0} bits)", entropy_bits)
+        }
+        audit::Issue::MatchesName => "password contains the entry name".to_string(),
+        audit::Issue::MatchesUser => "password contains the user name".to_string(),
+        audit::Issue::Stale { days: Some(days) } => {
+            format!("password not changed in {} days", days)
+        }
+        audit::Issue::Stale { days: None } => "password change time unknown".to_string(),
+    }
+}
+
+/// Checks all entries for empty, reused, weak and old passwords.
+fn audit_entries(path: &Path, options: audit::Options, json: bool) {
+    let entries = match unlock_file(path).and_then(|vault| vault.get_all()) {
+        Err(e) => {
+            println!("Error retrieving entries: {}", e);
+            return;
+        }
+        Ok(entries) => entries,
+    };
+
+    let report = audit::audit(&entries, &options, time::now());
+
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&report).expect(MSG_ENCODE_ERR)
+        );
+        return;
+    }
+
+    if report.findings.is_empty() {
+        println!(
+            "Audited {} entries, no issues found.",
+            report.entries_checked
+        );
+        return;
+    }
+
+    println!(
+        "Audited {} entries, found {} issues:",
+        report.entries_checked,
+        report.findings.len()
+    );
+
+    for finding in report.findings {
+        println!(
+            "  {}: {}",
+            finding.entry,
+            describe_issue(&finding.issue, &options)
+        );
+    }
+}
+
+/// Looks for the passwords of all entries in a local copy of the Have I Been
+/// Pwned database. Nothing is sent over the network.
+fn check_breaches(path: &Path, db_path: &str) {
+    let db = match breach::Database::open(db_path) {
+        Ok(db) => db,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
+    let mut entries = match unlock_file(path).and_then(|vault| vault.get_all()) {
+        Err(e) => {
+            println!("Error retrieving entries: {}", e);
+            return;
+        }
+        Ok(entries) => entries,
+    };
+    entries.sort_by(|(name1, _), (name2, _)| name1.cmp(name2));
+
+    let mut compromised = Vec::new();
+
+    for (name, entry) in entries.iter().filter(|(_, entry)| !entry.pw.is_empty()) {
+        match db.count(&entry.pw) {
+            Ok(0) => (),
+            Ok(count) => compromised.push((name, count)),
+            Err(e) => println!("Could not check entry `{}`. Reason: {}", name, e),
+        }
+    }
+
+    if compromised.is_empty() {
+        println!("None of the passwords was found in the breach database.");
+        return;
+    }
+
+    println!("Compromised passwords ({}):", compromised.len());
+
+    for (name, count) in compromised {
+        println!("  {} (seen {} times in breaches)", name, count);
+    }
+}
+
+/// Copies the entry `entry_name` to the vault `target`, with name
+/// `new_name`.
+fn copy_entry(path: &Path, config: &Config, entry_name: &str, target: &str, new_name: &str) {
+    let target_path = match config.vault_path(Some(target)) {
+        Ok(target_path) if target_path.exists() => target_path,
+        Ok(_) => {
+            println!("The data file of vault `{}` was not created yet.", target);
+            return;
+        }
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
+    let source = unlock_file(path).and_then(|vault| {
+        let entry = vault.get(entry_name)?;
+        if entry.is_none() {
+            return Ok(None);
+        }
+        let attachments = vault
+            .attachments(entry_name)?
+            .into_iter()
+            .map(|info| {
+                let data = vault.extract(entry_name, &info.name)?;
+                Ok((info, data))
+            })
+            .collect::<guarakapa::Result<Vec<_>>>()?;
+        Ok(entry.map(|entry| (entry, attachments)))
+    });
+    let (entry, attachments) = match source {
+        Ok(Some(source)) => source,
+        Ok(None) => {
+            println!("Entry `{}` not found.", entry_name);
+            return;
+        }
+        Err(e) => {
+            println!("Error retrieving entry. Reason: {}", e);
+            return;
+        }
+    };
+
+    let prompt = format!("Enter the master password of vault `{}`: ", target);
+    let mut target_vault = match unlock_file_with_prompt(&target_path, &prompt) {
+        Ok(vault) => vault,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
+    if let Err(e) = target_vault.insert(new_name.to_string(), entry) {
+        println!("Could not copy entry. Reason: {}", e);
+        return;
+    }
+    for (info, data) in attachments {
+        if let Err(e) = target_vault.insert_attachment(new_name, info, &data) {
+            println!("Could not copy attachments. Reason: {}", e);
+            return;
+        }
+    }
+
+    save_vault(&target_path, config, &mut target_vault);
+    println!(
+        "Entry '{}' copied to vault `{}` as '{}'.",
+        entry_name, target, new_name
+    );
+}
+
+/// Lists the vaults with the paths of their data files and the versions of
+/// the program that created them.
+fn list_vaults(config: &Config) {
+    let default = config.default_vault_name();
+    let vaults = match config.vaults() {
+        Ok(vaults) => vaults,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
+    let width = vaults
+        .iter()
+        .map(|(name, _)| name.chars().count())
+        .max()
+        .unwrap_or(0);
+
+    for (name, path) in vaults {
+        let version = match fs::load_from(&path) {
+            Ok(contents) => format!("version {}", codec::get_version(&contents)),
+            Err(_) => "not created yet".to_string(),
+        };
+
+        println!(
+            "{} {:width$}  {}  ({})",
+            if name == default { '*' } else { ' ' },
+            name,
+            path.display(),
+            version,
+            width = width
+        );
+    }
+}
+
+/// Lists or registers vaults and sets the default one, returning whether
+/// `params` are valid. Changes are made to
+/// `file_config` (without overrides), and saved.
+fn manage_vaults(mut file_config: Config, config: &Config, params: &[&str]) -> bool {
+    let result = match params {
+        [] => {
+            list_vaults(config);
+            return true;
+        }
+        ["add", name, path] => file_config.add_vault(name, Path::new(path)),
+        ["rm", name] => file_config.remove_vault(name),
+        ["default", name] => file_config.set_default_vault(name),
+        _ => return false,
+    };
+
+    match result.and_then(|_| file_config.save()) {
+        Ok(()) => println!("Vaults updated."),
+        Err(e) => println!("{}", e),
+    }
+
+    true
+}
+
+/// Shows or changes settings. Changes are made to `file_config` (without
+/// overrides), and saved.
+fn manage_config(mut file_config: Config, config: &Config, params: &[&str]) -> bool {
+    match params {
+        [] | ["show"] => match fs::config_path()
+            .and_then(|path| Ok(format!("# {}\n{}", path.display(), config.show()?)))
+        {
+            Ok(settings) => println!("{}", settings),
+            Err(e) => println!("{}", e),
+        },
+        ["get", key] => match config.get(key) {
+            Ok(Some(value)) => println!("{}", value),
+            Ok(None) => println!("`{}` is not set.", key),
+            Err(e) => println!("{}", e),
+        },
+        ["set", key, value] => match file_config.set(key, value).and_then(|_| file_config.save()) {
+            Ok(()) => println!("`{}` set to `{}`.", key, value),
+            Err(e) => println!("{:#}", e),
+        },
+        _ => return false,
+    }
+
+    true
+}
+
+fn show_generated_password(config: &Config) {
+    match generator::generate(&config.generator) {
+        Ok(pw) => println!("{}", &*pw),
+        Err(e) => println!("Could not generate password. Reason: {}", e),
+    }
+}
+
+fn show_file_path(path: &Path) {
+    println!("data file path: {}", path.display());
 }
 
 fn show_version() {
@@ -202,45 +1988,337 @@ fn check_file(file_path: &str) {
     );
 }
 
+/// Shows the help text for arguments that could not be parsed, and exits
+/// with a non-zero status so that scripts can tell.
+fn show_usage_error(exec_name: &str) -> ! {
+    show_help(exec_name);
+    std::process::exit(1);
+}
+
 fn show_help(exec_name: &str) {
     println!(
-        "First time usage: {exec}\n\
-    General usage: {exec} [OPTION] [COMMAND] [PARAMS]\n\n\
+        "First time usage: {exec} [--decoy]\n\
+    General usage: {exec} [--vault NAME] [OPTION] [COMMAND] [PARAMS]\n\n\
     Commands:\n  \
       [get] ENTRY\tRetrieve the entry with name `ENTRY`\n  \
-      add ENTRY\tAdd a new entry with name `ENTRY`\n  \
+      add ENTRY [-g] [--group NAME]\tAdd a new entry with name `ENTRY` \
+    (`-g`, `--generate`: with a random password; `--group`: in the group \
+    `NAME` of the team vault)\n  \
       rm ENTRY\tRemove the entry with name `ENTRY`\n  \
       check FILE\tShow the version of {program} used to create file in path \
     `FILE`\n  \
-      ls\t\tList all entries\n  \
+      ls [OPTIONS]\tList all entries\n  \
+      audit [OPTIONS]\tReport empty, reused, weak and old passwords\n  \
+      breach-check --db PATH\tFind entries whose password appears in a \
+    local copy of the Have I Been Pwned SHA-1 database at `PATH` (a sorted \
+    hash file or a directory of range files)\n  \
+      diff OLD NEW [OPTIONS]\tShow the entries added, removed, renamed \
+    and modified in the data file `NEW` with respect to `OLD` (e.g. a \
+    backup)\n  \
+      unlock\tKeep the key of the data file in the agent (`kapa-agent`) \
+    so that commands do not ask for the master password. Until it is \
+    forgotten, any program running as your user can get the key from the \
+    agent and read all the entries\n  \
+      lock\t\tMake the agent forget all keys\n  \
+      keyfile create PATH\tCreate a random key file at `PATH`, which the \
+    vault then requires besides the master password (see `--keyfile`)\n  \
+      challenge enable\tMake the vault require the response of the \
+    `challenge.command` setting (e.g. a hardware key) to a challenge, \
+    which changes on every save\n  \
+      challenge disable\tStop requiring the response to a challenge\n  \
+      attach ENTRY FILE\tAttach the file at `FILE` (e.g. an SSH key, up to \
+    1 MiB) to the entry `ENTRY`, encrypted in the data file\n  \
+      attachments ENTRY\tList the files attached to the entry `ENTRY`\n  \
+      extract ENTRY NAME --out PATH\tWrite the file `NAME` attached to the \
+    entry `ENTRY` to a new file at `PATH`, readable only by you\n  \
+      detach ENTRY NAME\tRemove the file `NAME` attached to the entry \
+    `ENTRY`\n  \
+      padding [MODE]\tShow or change how much the data file tells about \
+    the entries: `off`, `entries` (pad entries to powers of two bytes and add \
+    dummy entries up to a power of two) or `table` (also encrypt all the \
+    entries together, so that changes to single entries do not show, at the \
+    cost of decrypting them all on every use)\n  \
+      recovery create\tCreate a recovery key, which opens the vault \
+    without the master password (kept in a key slot of the data file)\n  \
+      recovery use\tSet a new master password with a recovery key (the \
+    key file and challenge are no longer required)\n  \
+      recovery list\tList the recovery key slots of the vault\n  \
+      recovery revoke ID\tRemove the recovery key slot `ID`, so that its \
+    recovery key no longer opens the vault\n  \
+      escrow split -k K -n N\tCreate a recovery key split into `N` shares, \
+    any `K` of which open the vault (e.g. to hand to different people)\n  \
+      escrow combine\tSet a new master password with shares of a recovery \
+    key\n  \
+      cp ENTRY VAULT [NEW_NAME]\tCopy the entry `ENTRY` to the vault \
+    `VAULT` (as `NEW_NAME`)\n  \
+      merge FILE [--base BASE]\tMerge the entries of `FILE`, another \
+    copy of the data file, given the copy `BASE` they both came from (asking \
+    which version to keep of entries changed on both sides)\n  \
+      sync\t\tCommit the data file to its git repository, and pull and \
+    push changes to the remote in `sync.remote` (merging entries changed on \
+    both sides)\n  \
+      member key [--rsa]\tShow your public key, to join team vaults \
+    (a keypair is created in your personal vault, the default one, on first \
+    use)\n  \
+      member add NAME [KEY_FILE]\tGive `NAME` access to the team vault, with \
+    the public key in `KEY_FILE` (without it, creates the team vault with \
+    you as `NAME`)\n  \
+      member remove NAME\tRemove `NAME` from the team vault, changing its \
+    key\n  \
+      member list\tList the members of the team vault\n  \
+      group create NAME\tCreate the group `NAME` in the team vault, with \
+    you as its only member (only the members of a group can read its \
+    entries)\n  \
+      group add NAME MEMBER\tAdd `MEMBER` to the group `NAME`\n  \
+      group remove NAME MEMBER\tRemove `MEMBER` from the group `NAME`, \
+    changing its key\n  \
+      group list\tList the groups of the team vault, with their members\n  \
+      vaults\tList the vaults, with their data files and versions (`*` \
+    marks the default one)\n  \
+      vaults add NAME PATH\tRegister the vault `NAME`, with data file in \
+    `PATH` (run `{exec} --vault NAME` to create it)\n  \
+      vaults rm NAME\tUnregister the vault `NAME` (its data file is kept)\n  \
+      vaults default NAME\tUse the vault `NAME` when none is selected\n  \
+      gen\t\tShow a random password (see the `generator` settings)\n  \
+      config [show]\tShow the settings in effect\n  \
+      config get KEY\tShow the value of the setting `KEY` (e.g. \
+    `clipboard.timeout`)\n  \
+      config set KEY VALUE\tChange the setting `KEY` in the config file\n  \
       path\t\tShow the path to {program}'s data file\n  \
       version\tShow the program version\n\n\
     Options:\n  \
+      --vault NAME\tUse the vault `NAME` instead of the default one\n  \
+      --keyfile PATH\tUnlock the vault with the key file at `PATH` besides \
+    the master password (a new vault then requires it)\n  \
+      --read-only\tNever write to the data file (e.g. on read-only media); \
+    commands changing it are refused\n  \
+      -o, --option KEY=VALUE\tOverride the setting `KEY` (settings can \
+    also be overridden by environment variables, e.g. \
+    `GUARAKAPA_CLIPBOARD_TIMEOUT`)\n  \
       -h, --help\tShow the help text\n  \
-      -v, --version\tShow the program version",
+      -v, --version\tShow the program version\n\n\
+    Options for ls:\n  \
+      -l, --long\tShow when entries were created, modified and used\n  \
+      --sort KEY\tSort by `name`, `created`, `modified`, `pw-changed` or \
+    `used` (most recent first)\n  \
+      --json\tOutput the list as JSON\n\n\
+    Options for audit:\n  \
+      --days N\tReport passwords not changed in `N` days (default: {max_age})\n  \
+      --months N\tSame as `--days`, in months of 30 days\n  \
+      --json\tOutput the report as JSON\n\n\
+    Options for diff:\n  \
+      --show-secrets\tShow the old and new passwords of modified entries\n  \
+      --json\tOutput the differences as JSON\n\n\
+    Options for first time usage:\n  \
+      --decoy\tAlso set a decoy password, which opens a separate vault \
+    in the same data file (to give away under duress). Without the passwords, \
+    the file does not tell whether it has a decoy (which can be left blank, \
+    to add none), but copies of it from different times show which vault \
+    changed. Such vaults only have a master password, without key files, \
+    challenges or recovery keys",
         exec = exec_name,
+        max_age = audit::Options::default().max_age_days,
         program = env!("CARGO_PKG_NAME")
     );
 }
 
 fn main() {
     let args: Vec<String> = env::args().collect();
+    let params: Vec<&str> = args[1..].iter().map(String::as_str).collect();
+
+    let mut params = params.as_slice();
+    let mut vault = None;
+    let mut read_only = false;
+    let mut key_file = None;
+    let mut overrides = Config::env_overrides(|name| env::var(name).ok());
+
+    loop {
+        match params {
+            ["--vault", name, rest @ ..] => {
+                vault = Some(*name);
+                params = rest;
+            }
+            ["--read-only", rest @ ..] => {
+                read_only = true;
+                params = rest;
+            }
+            ["--keyfile", path, rest @ ..] => {
+                key_file = Some(*path);
+                params = rest;
+            }
+            ["-o" | "--option", option, rest @ ..] => match option.split_once('=') {
+                Some((key, value)) => {
+                    overrides.push((key.to_string(), value.to_string()));
+                    params = rest;
+                }
+                None => show_usage_error(&args[0]),
+            },
+            _ => break,
+        }
+    }
+
+    let _ = READ_ONLY.set(read_only);
+
+    if !read_only {
+        match fs::migrate_legacy_files() {
+            Ok(moved) => {
+                for path in moved {
+                    println!(
+                        "Moved `{}` to the new location (see `path` and `config`).",
+                        path.display()
+                    );
+                }
+            }
+            Err(e) => {
+                println!("Could not move files from the old location. Reason: {}", e);
+                return;
+            }
+        }
+    }
+
+    let file_config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            println!("{:#}", e);
+            return;
+        }
+    };
+
+    let config = match file_config.clone().with_overrides(&overrides) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("{:#}", e);
+            return;
+        }
+    };
+
+    let path = match config.vault_path(vault) {
+        Ok(path) => path,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
+    if let Some(key_file) = key_file {
+        match std::fs::read(key_file) {
+            Ok(contents) => {
+                let _ = FACTORS.set(Factors::with_key_file(&contents));
+            }
+            Err(e) => {
+                println!("Could not read the key file. Reason: {}", e);
+                return;
+            }
+        }
+    }
 
-    match (fs::file_exists(), args.len() - 1) {
-        (_, 1) if vec!["version", "--version", "-v"].contains(&args[1].as_str()) => show_version(),
-        (_, 1) if vec!["--help", "-h"].contains(&args[1].as_str()) => show_help(&args[0]),
-        (true, 1) if args[1] == "ls" => list_entries(),
-        (true, 1) if args[1] == "path" => show_file_path(),
-        (true, 1) => get_entry(&args[1]),
-        (true, 2) if args[1] == "add" => add_entry(&args[2]),
-        (true, 2) if args[1] == "get" => get_entry(&args[2]),
-        (true, 2) if args[1] == "rm" => remove_entry(&args[2]),
-        (true, 2) if args[1] == "check" => check_file(&args[2]),
-        (true, _) => show_help(&args[0]),
-        (false, n) if n > 0 => {
+    if let Some(command) = &config.challenge.command {
+        let _ = CHALLENGE_COMMAND.set(command.clone());
+    }
+
+    match config.vault_path(None) {
+        Ok(personal_path) => {
+            let _ = PERSONAL_VAULT_PATH.set(personal_path);
+        }
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    }
+
+    let json = config.output == OutputFormat::Json;
+
+    match (path.exists(), params) {
+        (_, ["version" | "--version" | "-v"]) => show_version(),
+        (_, ["--help" | "-h"]) => show_help(&args[0]),
+        (_, ["vaults", params @ ..]) => {
+            if !manage_vaults(file_config, &config, params) {
+                show_usage_error(&args[0]);
+            }
+        }
+        (_, ["config", params @ ..]) => {
+            if !manage_config(file_config, &config, params) {
+                show_usage_error(&args[0]);
+            }
+        }
+        (_, ["gen"]) => show_generated_password(&config),
+        (true, ["ls", opts @ ..]) => match parse_list_opts(opts) {
+            Some((long, sort, json_opt)) => list_entries(&path, long, sort, json || json_opt),
+            None => show_usage_error(&args[0]),
+        },
+        (true, ["audit", opts @ ..]) => match parse_audit_opts(opts) {
+            Some((options, json_opt)) => audit_entries(&path, options, json || json_opt),
+            None => show_usage_error(&args[0]),
+        },
+        (_, ["diff", old, new, opts @ ..]) => match parse_diff_opts(opts) {
+            Some((show_secrets, json_opt)) => diff_files(old, new, show_secrets, json || json_opt),
+            None => show_usage_error(&args[0]),
+        },
+        (true, ["breach-check", "--db", db_path]) => check_breaches(&path, db_path),
+        (_, ["lock"]) => lock_agent(),
+        (true, ["unlock"]) => unlock_agent(&path),
+        (true, ["path"]) => show_file_path(&path),
+        (_, ["member", "key"]) => show_public_key(&config, false, read_only),
+        (_, ["member", "key", "--rsa"]) => show_public_key(&config, true, read_only),
+        (_, ["member", "add", name]) => add_member(&path, &config, name, None),
+        (true, ["member", "add", name, key_file]) => {
+            add_member(&path, &config, name, Some(key_file))
+        }
+        (true, ["member", "remove", name]) => remove_member(&path, &config, name),
+        (true, ["member", "list"]) => list_members(&path),
+        (true, ["group", "list"]) => list_groups(&path),
+        (true, ["group", "create", name]) => create_group(&path, &config, name),
+        (true, ["group", "add", group, member]) => {
+            change_group(&path, &config, group, member, true)
+        }
+        (true, ["group", "remove", group, member]) => {
+            change_group(&path, &config, group, member, false)
+        }
+        (true, ["add", name, opts @ ..]) => match parse_add_opts(opts) {
+            Some((generate, group)) => add_entry(&path, &config, name, generate, group),
+            None => show_usage_error(&args[0]),
+        },
+        (true, ["get", name]) => get_entry(&path, &config, name),
+        (true, ["rm", name]) => remove_entry(&path, &config, name),
+        (true, ["cp", name, target]) => copy_entry(&path, &config, name, target, name),
+        (true, ["cp", name, target, new_name]) => {
+            copy_entry(&path, &config, name, target, new_name)
+        }
+        (true, ["merge", other]) => merge_file(&path, &config, other, None),
+        (true, ["merge", other, "--base", base]) => merge_file(&path, &config, other, Some(base)),
+        (_, ["sync"]) => sync_vault(&path, &config),
+        (true, ["keyfile", "create", key_file]) => create_key_file(&path, &config, key_file),
+        (true, ["challenge", "enable"]) => change_challenge(&path, &config, true),
+        (true, ["challenge", "disable"]) => change_challenge(&path, &config, false),
+        (true, ["attach", name, file]) => attach_file(&path, &config, name, file),
+        (true, ["attachments", name]) => list_attachments(&path, name),
+        (true, ["extract", name, attachment, "--out", out]) => {
+            extract_attachment(&path, name, attachment, out)
+        }
+        (true, ["detach", name, attachment]) => detach_file(&path, &config, name, attachment),
+        (true, ["padding"]) => show_padding(&path),
+        (true, ["padding", padding]) => match Padding::parse(padding) {
+            Some(padding) => change_padding(&path, &config, padding),
+            None => show_usage_error(&args[0]),
+        },
+        (true, ["recovery", "create"]) => create_recovery_key(&path, &config),
+        (true, ["recovery", "use"]) => use_recovery_key(&path, &config),
+        (true, ["recovery", "list"]) => list_recovery_slots(&path),
+        (true, ["recovery", "revoke", id]) => revoke_recovery_slot(&path, &config, id),
+        (true, ["escrow", "split", opts @ ..]) => match parse_split_opts(opts) {
+            Some((threshold, count)) => split_recovery_key(&path, &config, threshold, count),
+            None => show_usage_error(&args[0]),
+        },
+        (true, ["escrow", "combine"]) => combine_shares(&path, &config),
+        (true, ["check", path]) => check_file(path),
+        (true, [name]) => get_entry(&path, &config, name),
+        (true, _) => show_usage_error(&args[0]),
+        (false, ["--decoy"]) if !read_only => create_new_file(&path, &config, true),
+        (false, params) if !params.is_empty() => {
             println!("Password file not found!\nIs this your first time usage?\n");
             show_help(&args[0]);
         }
-        _ => create_new_file(),
+        _ if read_only => println!("Password file not found!\n{}", MSG_READ_ONLY),
+        _ => create_new_file(&path, &config, false),
     }
 }
@@ -0,0 +1,451 @@
+//! Entry-level three-way merge of two versions of a vault that were changed
+//! independently (e.g. on two computers), given the version they both came
+//! from.
+//!
+//! Entries are matched by name. An entry changed on one side only takes that
+//! change; an entry changed on both sides in different ways is a conflict,
+//! resolved by the caller. Differences in the time an entry was last used
+//! alone are never a conflict: the latest time is kept.
+//!
+//! The files attached to each entry are merged the same way, by name. When
+//! both sides changed an attachment in different ways, both versions are
+//! kept, theirs with `.theirs` after its name.
+//!
+//! In team vaults, entries added on their side go to the same group as
+//! there. Entries of groups the user is not in are left as they are on our
+//! side.
+
+use crate::{
+    crypto::SecretBytes,
+    error::{Error, Result},
+    fman::{AttachmentInfo, OpenEntry, UnlockedVault},
+};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Version of a conflicting entry to keep
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Side {
+    Ours,
+    Theirs,
+}
+
+/// Entry changed on both sides in different ways. A missing version means
+/// the entry was removed on that side (or never added).
+pub struct Conflict<'a> {
+    pub name: &'a str,
+    pub ours: Option<&'a OpenEntry>,
+    pub theirs: Option<&'a OpenEntry>,
+}
+
+/// Changes made to our vault by a merge
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Summary {
+    pub added: usize,
+    pub updated: usize,
+    pub removed: usize,
+    /// Conflicts resolved by the caller
+    pub conflicts: usize,
+}
+
+impl Summary {
+    pub fn is_empty(&self) -> bool {
+        self.added + self.updated + self.removed == 0
+    }
+}
+
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} added, {} updated, {} removed ({} conflicts)",
+            self.added, self.updated, self.removed, self.conflicts
+        )
+    }
+}
+
+/// Merges the changes made in `theirs` into `ours`. Without a `base`, the
+/// entries of both vaults are kept, and only entries present on both sides
+/// with different contents are conflicts. `resolve` is called for each
+/// conflict, in alphabetical order.
+pub fn merge(
+    ours: &mut UnlockedVault,
+    base: Option<&UnlockedVault>,
+    theirs: &UnlockedVault,
+    mut resolve: impl FnMut(&Conflict) -> Side,
+) -> Result<Summary> {
+    let our_entries: BTreeMap<_, _> = ours.get_all()?.into_iter().collect();
+    let their_entries: BTreeMap<_, _> = theirs.get_all()?.into_iter().collect();
+    let base_entries: Option<BTreeMap<_, _>> = match base {
+        Some(base) => Some(base.get_all()?.into_iter().collect()),
+        None => None,
+    };
+
+    let names: BTreeSet<&String> = our_entries.keys().chain(their_entries.keys()).collect();
+    let mut summary = Summary::default();
+
+    for name in names {
+        let our_entry = our_entries.get(name);
+        let their_entry = their_entries.get(name);
+
+        let merged = if same(our_entry, their_entry) {
+            our_entry
+        } else {
+            match &base_entries {
+                Some(base) if same(base.get(name), our_entry) => their_entry,
+                Some(base) if same(base.get(name), their_entry) => our_entry,
+                None if our_entry.is_none() || their_entry.is_none() => our_entry.or(their_entry),
+                _ => {
+                    summary.conflicts += 1;
+                    let conflict = Conflict {
+                        name,
+                        ours: our_entry,
+                        theirs: their_entry,
+                    };
+
+                    match resolve(&conflict) {
+                        Side::Ours => our_entry,
+                        Side::Theirs => their_entry,
+                    }
+                }
+            }
+        };
+
+        let merged = merged.map(|entry| OpenEntry {
+            last_used: our_entry
+                .and_then(|e| e.last_used)
+                .max(their_entry.and_then(|e| e.last_used)),
+            ..entry.clone()
+        });
+
+        match (our_entry, merged) {
+            (None, None) => (),
+            (Some(_), None) => {
+                ours.remove(name)?;
+                summary.removed += 1;
+            }
+            (None, Some(entry)) => {
+                ours.insert_in_group(name.clone(), entry, theirs.group_of(name).as_deref())?;
+                // all the attachments of the entry come from their side
+                merge_attachments(ours, None, theirs, name)?;
+                summary.added += 1;
+            }
+            (Some(our_entry), Some(entry)) => {
+                let mut updated = !same(Some(our_entry), Some(&entry));
+                if *our_entry != entry {
+                    ours.put(name.clone(), entry)?;
+                }
+                if merge_attachments(ours, base, theirs, name)? {
+                    updated = true;
+                }
+                if updated {
+                    summary.updated += 1;
+                }
+            }
+        }
+    }
+
+    Ok(summary)
+}
+
+/// Attachments of the entry `name`, with their contents, by name. Vaults
+/// without the entry have none.
+fn attachments(
+    vault: &UnlockedVault,
+    name: &str,
+) -> Result<BTreeMap<String, (AttachmentInfo, SecretBytes)>> {
+    let infos = match vault.attachments(name) {
+        Ok(infos) => infos,
+        Err(Error::EntryNotFound(_)) => return Ok(BTreeMap::new()),
+        Err(e) => return Err(e),
+    };
+
+    infos
+        .into_iter()
+        .map(|info| {
+            let data = vault.extract(name, &info.name)?;
+            Ok((info.name.clone(), (info, data)))
+        })
+        .collect()
+}
+
+/// Merges the attachments of the entry `name` from `theirs` into `ours`.
+/// Returns whether ours changed.
+fn merge_attachments(
+    ours: &mut UnlockedVault,
+    base: Option<&UnlockedVault>,
+    theirs: &UnlockedVault,
+    name: &str,
+) -> Result<bool> {
+    let our_attachments = attachments(ours, name)?;
+    let their_attachments = attachments(theirs, name)?;
+    let base_attachments = match base {
+        Some(base) => Some(attachments(base, name)?),
+        None => None,
+    };
+
+    let same = |a: Option<&(AttachmentInfo, SecretBytes)>,
+                b: Option<&(AttachmentInfo, SecretBytes)>| match (a, b) {
+        (Some((_, a)), Some((_, b))) => a[..] == b[..],
+        (a, b) => a.is_none() && b.is_none(),
+    };
+
+    let names: BTreeSet<&String> = our_attachments
+        .keys()
+        .chain(their_attachments.keys())
+        .collect();
+    let mut changed = false;
+
+    for attachment in names {
+        let ours_now = our_attachments.get(attachment);
+        let theirs_now = their_attachments.get(attachment);
+        if same(ours_now, theirs_now) {
+            continue;
+        }
+
+        let base_now = base_attachments.as_ref().map(|base| base.get(attachment));
+        match (base_now, ours_now, theirs_now) {
+            // changed on their side only
+            (Some(base_now), _, _) if same(base_now, ours_now) => {
+                if ours_now.is_some() {
+                    ours.detach(name, attachment)?;
+                }
+                if let Some((info, data)) = theirs_now {
+                    ours.insert_attachment(name, info.clone(), data)?;
+                }
+                changed = true;
+            }
+            // changed on our side only
+            (Some(base_now), _, _) if same(base_now, theirs_now) => (),
+            // removed on one side, added or changed on the other
+            (_, _, None) => (),
+            (_, None, Some((info, data))) => {
+                ours.insert_attachment(name, info.clone(), data)?;
+                changed = true;
+            }
+            (_, Some(_), Some((info, data))) => {
+                let renamed = AttachmentInfo {
+                    name: format!("{}.theirs", attachment),
+                    ..info.clone()
+                };
+                if !our_attachments.contains_key(&renamed.name) {
+                    ours.insert_attachment(name, renamed, data)?;
+                    changed = true;
+                }
+            }
+        }
+    }
+
+    Ok(changed)
+}
+
+/// Whether two versions of an entry are the same, apart from the time they
+/// were last used
+fn same(a: Option<&OpenEntry>, b: Option<&OpenEntry>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => {
+            OpenEntry {
+                last_used: None,
+                ..a.clone()
+            } == OpenEntry {
+                last_used: None,
+                ..b.clone()
+            }
+        }
+        (a, b) => a.is_none() && b.is_none(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{crypto::Kdf, fman::File};
+
+    const PW: &str = "master";
+
+    fn entry(pw: &str) -> OpenEntry {
+        OpenEntry {
+            pw: pw.into(),
+            ..Default::default()
+        }
+    }
+
+    fn vault(entries: &[(&str, &str)]) -> UnlockedVault {
+        let mut vault = File::try_new_unlocked(PW, Kdf::Pbkdf2 { iterations: 1000 }).unwrap();
+
+        for (name, pw) in entries {
+            vault.insert(name.to_string(), entry(pw)).unwrap();
+        }
+
+        vault
+    }
+
+    /// Copy of `vault` sharing its key, as a clone in another repository
+    fn copy(vault: &mut UnlockedVault) -> UnlockedVault {
+        let bytes = crate::codec::encode(vault.seal().unwrap()).unwrap();
+        vault
+            .unlock_other(crate::codec::decode(&bytes).unwrap())
+            .unwrap()
+    }
+
+    fn passwords(vault: &UnlockedVault) -> Vec<(String, String)> {
+        vault
+            .get_all()
+            .unwrap()
+            .into_iter()
+            .map(|(name, entry)| (name, String::from(&*entry.pw)))
+            .collect()
+    }
+
+    #[test]
+    fn changes_on_either_side_are_kept() {
+        let mut base = vault(&[("a", "1"), ("b", "2"), ("c", "3"), ("d", "4")]);
+        let mut ours = copy(&mut base);
+        let mut theirs = copy(&mut base);
+
+        ours.put(String::from("a"), entry("ours")).unwrap();
+        ours.remove("b").unwrap();
+        ours.insert(String::from("e"), entry("5")).unwrap();
+        theirs.put(String::from("c"), entry("theirs")).unwrap();
+        theirs.remove("d").unwrap();
+        theirs.insert(String::from("f"), entry("6")).unwrap();
+
+        let summary = merge(&mut ours, Some(&base), &theirs, |_| panic!("conflict")).unwrap();
+
+        assert_eq!(
+            summary,
+            Summary {
+                added: 1,
+                updated: 1,
+                removed: 1,
+                conflicts: 0
+            }
+        );
+        let expected = [("a", "ours"), ("c", "theirs"), ("e", "5"), ("f", "6")];
+        assert_eq!(
+            passwords(&ours),
+            expected.map(|(n, p)| (n.to_string(), p.to_string()))
+        );
+    }
+
+    #[test]
+    fn conflicts_are_resolved_by_the_caller() {
+        let mut base = vault(&[("a", "1"), ("b", "2"), ("c", "3")]);
+        let mut ours = copy(&mut base);
+        let mut theirs = copy(&mut base);
+
+        ours.put(String::from("a"), entry("ours")).unwrap();
+        theirs.put(String::from("a"), entry("theirs")).unwrap();
+        ours.put(String::from("b"), entry("ours")).unwrap();
+        theirs.remove("b").unwrap();
+        // the same change on both sides is not a conflict
+        ours.put(String::from("c"), entry("both")).unwrap();
+        theirs.put(String::from("c"), entry("both")).unwrap();
+
+        let mut conflicts = Vec::new();
+        let summary = merge(&mut ours, Some(&base), &theirs, |conflict| {
+            conflicts.push(conflict.name.to_string());
+            assert!(conflict.ours.is_some());
+            Side::Theirs
+        })
+        .unwrap();
+
+        assert_eq!(conflicts, vec!["a", "b"]);
+        assert_eq!(summary.conflicts, 2);
+        assert_eq!(
+            passwords(&ours),
+            vec![
+                (String::from("a"), String::from("theirs")),
+                (String::from("c"), String::from("both"))
+            ]
+        );
+    }
+
+    fn attachment_names(vault: &UnlockedVault, name: &str) -> Vec<String> {
+        let mut names: Vec<String> = vault
+            .attachments(name)
+            .unwrap()
+            .into_iter()
+            .map(|info| info.name)
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn attachments_of_both_sides_are_kept() {
+        let mut base = vault(&[("a", "1")]);
+        base.attach("a", String::from("removed"), b"base").unwrap();
+        base.attach("a", String::from("changed"), b"base").unwrap();
+        base.attach("a", String::from("both"), b"base").unwrap();
+        let mut ours = copy(&mut base);
+        let mut theirs = copy(&mut base);
+
+        ours.attach("a", String::from("ours"), b"ours").unwrap();
+        theirs
+            .attach("a", String::from("theirs"), b"theirs")
+            .unwrap();
+        theirs.detach("a", "removed").unwrap();
+        theirs.detach("a", "changed").unwrap();
+        theirs
+            .attach("a", String::from("changed"), b"theirs")
+            .unwrap();
+        for (vault, data) in [(&mut ours, b"ours"), (&mut theirs, b"them")] {
+            vault.detach("a", "both").unwrap();
+            vault.attach("a", String::from("both"), data).unwrap();
+        }
+        theirs.insert(String::from("b"), entry("2")).unwrap();
+        theirs.attach("b", String::from("new"), b"new").unwrap();
+
+        let summary = merge(&mut ours, Some(&base), &theirs, |_| panic!("conflict")).unwrap();
+
+        assert_eq!(summary.added, 1);
+        assert_eq!(summary.updated, 1);
+        assert_eq!(
+            attachment_names(&ours, "a"),
+            ["both", "both.theirs", "changed", "ours", "theirs"]
+        );
+        assert_eq!(&ours.extract("a", "changed").unwrap()[..], b"theirs");
+        assert_eq!(&ours.extract("a", "both").unwrap()[..], b"ours");
+        assert_eq!(&ours.extract("a", "both.theirs").unwrap()[..], b"them");
+        assert_eq!(&ours.extract("b", "new").unwrap()[..], b"new");
+
+        // merging again changes nothing
+        let mut merged = copy(&mut ours);
+        let summary = merge(&mut merged, Some(&theirs), &ours, |_| panic!("conflict")).unwrap();
+        assert!(summary.is_empty());
+    }
+
+    #[test]
+    fn latest_use_is_kept_without_conflict() {
+        let mut base = vault(&[("a", "1")]);
+        let mut ours = copy(&mut base);
+        let mut theirs = copy(&mut base);
+
+        let used = |time| OpenEntry {
+            last_used: Some(time),
+            ..entry("1")
+        };
+        ours.put(String::from("a"), used(10)).unwrap();
+        theirs.put(String::from("a"), used(20)).unwrap();
+
+        let summary = merge(&mut ours, Some(&base), &theirs, |_| panic!("conflict")).unwrap();
+
+        assert!(summary.is_empty());
+        assert_eq!(ours.get("a").unwrap().unwrap().last_used, Some(20));
+    }
+
+    #[test]
+    fn without_base_entries_of_both_sides_are_kept() {
+        let mut ours = vault(&[("a", "1"), ("b", "2")]);
+        let theirs = vault(&[("b", "other"), ("c", "3")]);
+
+        let summary = merge(&mut ours, None, &theirs, |_| Side::Ours).unwrap();
+
+        assert_eq!(summary.added, 1);
+        assert_eq!(summary.conflicts, 1);
+        assert_eq!(
+            passwords(&ours),
+            [("a", "1"), ("b", "2"), ("c", "3")].map(|(n, p)| (n.to_string(), p.to_string()))
+        );
+    }
+}
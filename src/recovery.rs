@@ -0,0 +1,184 @@
+//! Recovery keys, opening a vault whose master password was forgotten.
+//!
+//! A recovery key is 200 random bits followed by a 40-bit checksum (catching
+//! typos), written in base32 (RFC 4648) in groups of four characters:
+//! `ABCD-EFGH-...`. The vault key is encrypted for an X25519 keypair derived
+//! from it, in a key slot of the data file (see `fman::RecoverySlot`), so
+//! that the slot can be updated when the vault key changes without knowing
+//! the recovery key.
+
+use crate::crypto::{self, Keypair, SecretBytes, SecretString};
+use crate::error::{Error, Result};
+use zeroize::Zeroize;
+
+const SECRET_LEN: usize = 25;
+const CHECKSUM_LEN: usize = 5;
+const GROUP_LEN: usize = 4;
+const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Separates the keypair of recovery keys from other uses of the same bytes
+const KEYPAIR_CONTEXT: &[u8] = b"guarakapa recovery key";
+
+pub struct RecoveryKey(SecretBytes);
+
+impl RecoveryKey {
+    pub fn generate() -> Self {
+        RecoveryKey(crypto::generate_bytes(SECRET_LEN).into())
+    }
+
+    /// Reads a recovery key written by `encode`, ignoring case, spaces and
+    /// dashes.
+    pub fn parse(text: &str) -> Result<Self> {
+        match decode_checked(text) {
+            Some(secret) if secret.len() == SECRET_LEN => Ok(RecoveryKey(secret)),
+            _ => Err(Error::InvalidRecoveryKey),
+        }
+    }
+
+    /// The recovery key as text, to be written down by the user
+    pub fn encode(&self) -> SecretString {
+        encode_checked(&self.0)
+    }
+
+    /// Random bytes the recovery key is made of, e.g. to split it (see
+    /// `escrow`)
+    pub fn secret(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn from_secret(secret: SecretBytes) -> Result<Self> {
+        match secret.len() {
+            SECRET_LEN => Ok(RecoveryKey(secret)),
+            _ => Err(Error::InvalidRecoveryKey),
+        }
+    }
+
+    /// Keypair the vault key is encrypted for in the key slot of this
+    /// recovery key
+    pub fn keypair(&self) -> Result<Keypair> {
+        let mut secret = crypto::hash(vec![KEYPAIR_CONTEXT, &self.0]);
+        let keypair = Keypair::x25519_from_secret(&secret);
+        secret.zeroize();
+        keypair
+    }
+}
+
+/// Writes `data` and its checksum in base32, in groups of four characters.
+/// `data` must be a multiple of 5 bytes long, so that no bits are left over.
+pub(crate) fn encode_checked(data: &[u8]) -> SecretString {
+    assert!(
+        data.len().is_multiple_of(5),
+        "data to encode must be a multiple of 5 bytes long"
+    );
+
+    let mut bytes = SecretBytes::zeroed(data.len() + CHECKSUM_LEN);
+    bytes[..data.len()].copy_from_slice(data);
+    bytes[data.len()..].copy_from_slice(&checksum(data));
+
+    let mut text = String::new();
+    let (mut buffer, mut bits, mut n_chars) = (0u32, 0, 0);
+
+    for byte in bytes.iter() {
+        buffer = ((buffer << 8) | *byte as u32) & 0xffff;
+        bits += 8;
+
+        while bits >= 5 {
+            bits -= 5;
+            if n_chars > 0 && n_chars % GROUP_LEN == 0 {
+                text.push('-');
+            }
+            text.push(ALPHABET[(buffer >> bits) as usize & 31] as char);
+            n_chars += 1;
+        }
+    }
+    buffer.zeroize();
+
+    text.into()
+}
+
+/// Reads data written by `encode_checked`, ignoring case, spaces and dashes,
+/// or `None` if it is not valid base32 or its checksum does not match.
+pub(crate) fn decode_checked(text: &str) -> Option<SecretBytes> {
+    let mut bytes = SecretBytes::zeroed(text.len() * 5 / 8);
+    let (mut buffer, mut bits, mut len) = (0u32, 0, 0);
+
+    for c in text.chars().filter(|c| !c.is_whitespace() && *c != '-') {
+        let value = ALPHABET
+            .iter()
+            .position(|&letter| letter as char == c.to_ascii_uppercase())?;
+        buffer = ((buffer << 5) | value as u32) & 0xffff;
+        bits += 5;
+
+        if bits >= 8 {
+            bits -= 8;
+            bytes[len] = (buffer >> bits) as u8;
+            len += 1;
+        }
+    }
+    buffer.zeroize();
+
+    if bits != 0 || len < CHECKSUM_LEN {
+        return None;
+    }
+
+    let (data, sum) = bytes[..len].split_at(len - CHECKSUM_LEN);
+    (sum == checksum(data)).then(|| SecretBytes::from(data))
+}
+
+fn checksum(data: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let mut checksum = [0; CHECKSUM_LEN];
+    checksum.copy_from_slice(&crypto::hash(vec![data])[..CHECKSUM_LEN]);
+    checksum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recovery_keys_survive_being_written_down() {
+        let key = RecoveryKey::generate();
+        let text = key.encode();
+        assert_eq!(text.len(), 48 + 11);
+        assert!(text
+            .split('-')
+            .all(|group| group.len() == GROUP_LEN && group.bytes().all(|c| ALPHABET.contains(&c))));
+
+        let parsed = RecoveryKey::parse(&text).unwrap();
+        assert_eq!(&parsed.0[..], &key.0[..]);
+
+        let sloppy = text.to_lowercase().replace('-', " ");
+        let parsed = RecoveryKey::parse(&sloppy).unwrap();
+        assert_eq!(&parsed.0[..], &key.0[..]);
+        assert_eq!(
+            parsed.keypair().unwrap().public_key().unwrap(),
+            key.keypair().unwrap().public_key().unwrap()
+        );
+    }
+
+    #[test]
+    fn typos_are_caught() {
+        let text = RecoveryKey::generate().encode();
+
+        let mut typo = text.to_string().into_bytes();
+        typo[0] = if typo[0] == b'A' { b'B' } else { b'A' };
+        let typo = String::from_utf8(typo).unwrap();
+
+        assert!(matches!(
+            RecoveryKey::parse(&typo),
+            Err(Error::InvalidRecoveryKey)
+        ));
+        assert!(matches!(
+            RecoveryKey::parse(&text[..text.len() - 1]),
+            Err(Error::InvalidRecoveryKey)
+        ));
+        assert!(matches!(
+            RecoveryKey::parse(&format!("{}A", &*text)),
+            Err(Error::InvalidRecoveryKey)
+        ));
+        assert!(matches!(
+            RecoveryKey::parse(&format!("0{}", &text[1..])),
+            Err(Error::InvalidRecoveryKey)
+        ));
+    }
+}
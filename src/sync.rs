@@ -0,0 +1,305 @@
+//! Synchronization of a data file through a git repository (e.g. to share
+//! it between computers), using the `git` program.
+//!
+//! Changes are committed to the repository in the directory of the data
+//! file, which is created if needed (even inside another repository, e.g.
+//! one of dotfiles, which is never used). Syncing pulls the changes of the remote
+//! and pushes the local ones. When both sides changed, the data file of each
+//! side and of their common ancestor are given to a merge function (see
+//! `merge`), and the result is committed as a merge of both histories, so
+//! that git never has to merge the data file itself. Commit messages do not
+//! mention entry names, which are only stored encrypted.
+
+use anyhow::{bail, Context, Result};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+const COMMIT_MESSAGE: &str = "Update vault";
+const MERGE_MESSAGE: &str = "Merge vault";
+
+/// Outcome of a sync
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    /// Both sides had the same changes
+    UpToDate,
+    /// Local changes were pushed to the remote
+    Pushed,
+    /// Changes of the remote were applied, and there were no local changes
+    Pulled,
+    /// Both sides had changes, which were merged and pushed
+    Merged,
+}
+
+/// Git repository holding a data file
+pub struct Repo {
+    dir: PathBuf,
+    file: String,
+}
+
+impl Repo {
+    /// Repository of the data file at `path`, which does not need to exist
+    /// yet
+    pub fn new(path: &Path) -> Result<Self> {
+        let file = match path.file_name() {
+            Some(file) => file.to_string_lossy().into_owned(),
+            None => bail!("Invalid data file path `{}`.", path.display()),
+        };
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+
+        Ok(Repo { dir, file })
+    }
+
+    /// Creates the repository, with `branch` as its branch, unless the
+    /// directory of the data file is the top of one already. A repository the
+    /// directory is only inside of is not the one of the vault.
+    pub fn init(&self, branch: &str) -> Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+
+        let output = self.git(&["rev-parse", "--show-toplevel"])?;
+        let is_top = output.status.success() && {
+            let top = PathBuf::from(String::from_utf8_lossy(&output.stdout).trim());
+            std::fs::canonicalize(top)? == std::fs::canonicalize(&self.dir)?
+        };
+
+        if !is_top {
+            self.run(&["init", "-q", "-b", branch])?;
+        }
+
+        Ok(())
+    }
+
+    /// Commits the data file, if it changed. Returns whether there was
+    /// something to commit.
+    pub fn commit(&self, branch: &str) -> Result<bool> {
+        self.init(branch)?;
+
+        if !self.dir.join(&self.file).exists() {
+            return Ok(false);
+        }
+
+        self.run(&["add", "--", &self.file])?;
+
+        if self
+            .git(&["diff", "--cached", "--quiet", "--", &self.file])?
+            .status
+            .success()
+        {
+            return Ok(false);
+        }
+
+        self.run(&["commit", "-q", "-m", COMMIT_MESSAGE, "--", &self.file])?;
+        Ok(true)
+    }
+
+    /// Pulls the changes of `branch` in `remote` and pushes the local ones,
+    /// committing local changes first. If both sides changed, `merge` is
+    /// called with the data file of the common ancestor (if it had one) and
+    /// of the remote, and returns the merged data file.
+    pub fn sync(
+        &self,
+        remote: &str,
+        branch: &str,
+        merge: impl FnOnce(Option<Vec<u8>>, Vec<u8>) -> Result<Vec<u8>>,
+    ) -> Result<Status> {
+        self.commit(branch)?;
+        let has_commits = self
+            .git(&["rev-parse", "-q", "--verify", "HEAD"])?
+            .status
+            .success();
+
+        let remote_branch = self.run(&["ls-remote", "--heads", remote, branch])?;
+        if remote_branch.is_empty() {
+            if !has_commits {
+                bail!("There is nothing to sync: no data file and no remote branch.");
+            }
+            self.push(remote, branch)?;
+            return Ok(Status::Pushed);
+        }
+
+        self.run(&["fetch", "-q", remote, branch])?;
+
+        if !has_commits {
+            self.run(&["checkout", "-q", "-B", branch, "FETCH_HEAD"])?;
+            return Ok(Status::Pulled);
+        }
+
+        if self.is_ancestor("FETCH_HEAD", "HEAD")? {
+            if self.is_ancestor("HEAD", "FETCH_HEAD")? {
+                return Ok(Status::UpToDate);
+            }
+            self.push(remote, branch)?;
+            return Ok(Status::Pushed);
+        }
+
+        if self.is_ancestor("HEAD", "FETCH_HEAD")? {
+            self.run(&["merge", "-q", "--ff-only", "FETCH_HEAD"])?;
+            return Ok(Status::Pulled);
+        }
+
+        let base = match self.run(&["merge-base", "HEAD", "FETCH_HEAD"]) {
+            Ok(base) => self.show(String::from_utf8_lossy(&base).trim()).ok(),
+            Err(_) => None,
+        };
+        let theirs = self.show("FETCH_HEAD")?;
+        let merged = merge(base, theirs)?;
+
+        // the history of both sides is kept, with the merged data file
+        self.run(&[
+            "merge",
+            "-q",
+            "--no-ff",
+            "--no-commit",
+            "--allow-unrelated-histories",
+            "-s",
+            "ours",
+            "FETCH_HEAD",
+        ])?;
+        crate::fs::save_atomically(self.dir.join(&self.file), merged)?;
+        self.run(&["add", "--", &self.file])?;
+        self.run(&["commit", "-q", "-m", MERGE_MESSAGE])?;
+
+        self.push(remote, branch)?;
+        Ok(Status::Merged)
+    }
+
+    fn push(&self, remote: &str, branch: &str) -> Result<()> {
+        self.run(&["push", "-q", remote, &format!("HEAD:refs/heads/{}", branch)])?;
+        Ok(())
+    }
+
+    fn is_ancestor(&self, ancestor: &str, commit: &str) -> Result<bool> {
+        let output = self.git(&["merge-base", "--is-ancestor", ancestor, commit])?;
+        Ok(output.status.success())
+    }
+
+    /// Contents of the data file in `commit`
+    fn show(&self, commit: &str) -> Result<Vec<u8>> {
+        self.run(&["show", &format!("{}:./{}", commit, self.file)])
+    }
+
+    /// Runs git, failing if it does not succeed. Returns its output.
+    fn run(&self, args: &[&str]) -> Result<Vec<u8>> {
+        let output = self.git(args)?;
+
+        if !output.status.success() {
+            bail!(
+                "`git {}` failed: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+
+        Ok(output.stdout)
+    }
+
+    fn git(&self, args: &[&str]) -> Result<Output> {
+        Command::new("git")
+            .arg("-C")
+            .arg(&self.dir)
+            .args(args)
+            .output()
+            .context("Could not run git")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BRANCH: &str = "main";
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("gk-sync-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn set_identity() {
+        for var in ["GIT_AUTHOR", "GIT_COMMITTER"] {
+            std::env::set_var(format!("{}_NAME", var), "test");
+            std::env::set_var(format!("{}_EMAIL", var), "test@example.com");
+        }
+    }
+
+    #[test]
+    fn merges_divergent_histories() {
+        set_identity();
+        let dir = temp_dir("merge");
+        let remote = dir.join("remote.git");
+        Command::new("git")
+            .args(["init", "-q", "--bare", "-b", BRANCH])
+            .arg(&remote)
+            .status()
+            .unwrap();
+        let remote = remote.to_str().unwrap();
+
+        let path_a = dir.join("a").join("gk.dat");
+        let path_b = dir.join("b").join("gk.dat");
+        let (a, b) = (Repo::new(&path_a).unwrap(), Repo::new(&path_b).unwrap());
+        let no_merge = |_: Option<Vec<u8>>, _: Vec<u8>| -> Result<Vec<u8>> { panic!("merge") };
+
+        assert!(b.sync(remote, BRANCH, no_merge).is_err());
+
+        std::fs::create_dir_all(path_a.parent().unwrap()).unwrap();
+        std::fs::write(&path_a, "base").unwrap();
+        assert_eq!(a.sync(remote, BRANCH, no_merge).unwrap(), Status::Pushed);
+        assert_eq!(b.sync(remote, BRANCH, no_merge).unwrap(), Status::Pulled);
+        assert_eq!(std::fs::read(&path_b).unwrap(), b"base");
+        assert_eq!(b.sync(remote, BRANCH, no_merge).unwrap(), Status::UpToDate);
+
+        std::fs::write(&path_a, "a").unwrap();
+        assert!(a.commit(BRANCH).unwrap());
+        assert!(!a.commit(BRANCH).unwrap());
+        assert_eq!(a.sync(remote, BRANCH, no_merge).unwrap(), Status::Pushed);
+
+        std::fs::write(&path_b, "b").unwrap();
+        let status = b
+            .sync(remote, BRANCH, |base, theirs| {
+                assert_eq!(base.unwrap(), b"base");
+                assert_eq!(theirs, b"a");
+                Ok(b"merged".to_vec())
+            })
+            .unwrap();
+        assert_eq!(status, Status::Merged);
+        assert_eq!(std::fs::read(&path_b).unwrap(), b"merged");
+
+        assert_eq!(a.sync(remote, BRANCH, no_merge).unwrap(), Status::Pulled);
+        assert_eq!(std::fs::read(&path_a).unwrap(), b"merged");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn enclosing_repositories_are_not_used() {
+        set_identity();
+        let dir = temp_dir("enclosing");
+        Command::new("git")
+            .args(["init", "-q", "-b", BRANCH])
+            .arg(&dir)
+            .status()
+            .unwrap();
+
+        let path = dir.join("data").join("gk.dat");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, "vault").unwrap();
+        let repo = Repo::new(&path).unwrap();
+        assert!(repo.commit(BRANCH).unwrap());
+
+        assert!(dir.join("data").join(".git").exists());
+        let outer = Command::new("git")
+            .arg("-C")
+            .arg(&dir)
+            .args(["rev-parse", "-q", "--verify", "HEAD"])
+            .status()
+            .unwrap();
+        assert!(!outer.success());
+
+        // the repository of the vault is used from then on
+        std::fs::write(&path, "changed").unwrap();
+        assert!(repo.commit(BRANCH).unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,79 @@
+//! Timestamps stored in the data file. They are kept as seconds since the
+//! Unix epoch (UTC), which is small to serialize and does not require any
+//! date/time dependency.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const SECS_PER_DAY: u64 = 86_400;
+
+/// Current time, in seconds since the Unix epoch
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// Number of whole days elapsed between `timestamp` and `now`
+pub fn days_since(timestamp: u64, now: u64) -> u64 {
+    now.saturating_sub(timestamp) / SECS_PER_DAY
+}
+
+/// Formats a timestamp as `YYYY-MM-DD HH:MM` (UTC). A zero timestamp means
+/// the time is not known (e.g. entries created before timestamps existed).
+pub fn format(timestamp: u64) -> String {
+    if timestamp == 0 {
+        return "unknown".to_string();
+    }
+
+    let (year, month, day) = civil_from_days((timestamp / SECS_PER_DAY) as i64);
+    let secs = timestamp % SECS_PER_DAY;
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60
+    )
+}
+
+/// Converts a number of days since 1970-01-01 into a (year, month, day) date
+/// of the proleptic Gregorian calendar.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_known_dates() {
+        assert_eq!(format(1), "1970-01-01 00:00");
+        assert_eq!(format(951_782_400), "2000-02-29 00:00");
+        assert_eq!(format(1_700_000_000), "2023-11-14 22:13");
+    }
+
+    #[test]
+    fn zero_timestamp_is_unknown() {
+        assert_eq!(format(0), "unknown");
+    }
+
+    #[test]
+    fn counts_elapsed_days() {
+        assert_eq!(days_since(0, SECS_PER_DAY * 3 + 5), 3);
+        assert_eq!(days_since(SECS_PER_DAY * 10, SECS_PER_DAY), 0);
+    }
+}
@@ -0,0 +1,363 @@
+//! High-level access to data files, for programs using guarakapa as a
+//! library. This module is part of the stable API (see the crate
+//! documentation).
+//!
+//! ```
+//! use guarakapa::{Entry, Vault};
+//!
+//! let path = std::env::temp_dir().join(format!("gk-doc-vault-{}.dat", std::process::id()));
+//! # let _ = std::fs::remove_file(&path);
+//! let mut vault = Vault::create(&path, "master password")?;
+//!
+//! let mut entry = Entry::new("hunter2");
+//! entry.user = String::from("me");
+//! vault.add("github", entry)?;
+//! vault.save()?;
+//!
+//! let vault = Vault::open(&path)?.unlock("master password")?;
+//! assert_eq!(vault.search("git"), vec!["github"]);
+//! assert_eq!(vault.get("github")?.user, "me");
+//! # std::fs::remove_file(&path)?;
+//! # Ok::<(), guarakapa::Error>(())
+//! ```
+
+use crate::{
+    codec,
+    crypto::Kdf,
+    error::{Error, Result},
+    fman::{self, File, OpenEntry},
+    fs,
+};
+use std::path::{Path, PathBuf};
+
+/// String wiped from memory when dropped, holding the password of an
+/// `Entry`. It dereferences to `str`.
+pub use crate::crypto::SecretString;
+
+/// A data file read from disk, still locked
+#[derive(Debug)]
+pub struct Vault {
+    path: PathBuf,
+    file: File,
+    read_only: bool,
+}
+
+/// A vault unlocked with its master password, whose entries can be read and
+/// changed. Changes are only written to disk by `save`.
+pub struct UnlockedVault {
+    path: PathBuf,
+    vault: fman::UnlockedVault,
+}
+
+/// An entry of a vault. Timestamps are seconds since the Unix epoch, with
+/// `0` meaning unknown, and are kept by the vault: changing them has no
+/// effect.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[non_exhaustive]
+pub struct Entry {
+    pub description: String,
+    pub user: String,
+    pub email: String,
+    pub notes: String,
+    pub password: SecretString,
+    pub created: u64,
+    pub modified: u64,
+    pub password_changed: u64,
+    pub last_used: Option<u64>,
+}
+
+impl Entry {
+    /// Creates an entry with the given password and all other fields empty.
+    pub fn new(password: impl Into<SecretString>) -> Self {
+        Entry {
+            password: password.into(),
+            ..Default::default()
+        }
+    }
+}
+
+impl From<OpenEntry> for Entry {
+    fn from(entry: OpenEntry) -> Self {
+        Entry {
+            description: entry.desc,
+            user: entry.user,
+            email: entry.email,
+            notes: entry.notes,
+            password: entry.pw,
+            created: entry.created,
+            modified: entry.modified,
+            password_changed: entry.pw_changed,
+            last_used: entry.last_used,
+        }
+    }
+}
+
+impl From<Entry> for OpenEntry {
+    fn from(entry: Entry) -> Self {
+        OpenEntry {
+            desc: entry.description,
+            user: entry.user,
+            email: entry.email,
+            notes: entry.notes,
+            pw: entry.password,
+            created: entry.created,
+            modified: entry.modified,
+            pw_changed: entry.password_changed,
+            last_used: entry.last_used,
+        }
+    }
+}
+
+impl Vault {
+    /// Reads the data file at `path`.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let contents = fs::load_from(&path)?;
+        let file = codec::decode(contents.as_slice())?;
+
+        Ok(Vault {
+            path,
+            file,
+            read_only: false,
+        })
+    }
+
+    /// Reads the data file at `path`, which is never written: changing the
+    /// vault fails with `Error::ReadOnly`. The file only needs to be
+    /// readable (e.g. on read-only media).
+    pub fn open_read_only(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Vault {
+            read_only: true,
+            ..Self::open(path)?
+        })
+    }
+
+    /// Creates an empty data file at `path`, protected by `password`, and
+    /// returns it unlocked. Fails if there is a file at `path` already, even
+    /// one created while the key was being derived.
+    pub fn create(path: impl AsRef<Path>, password: &str) -> Result<UnlockedVault> {
+        let mut vault = UnlockedVault {
+            vault: File::try_new_unlocked(password, Kdf::default())?,
+            path: path.as_ref().to_path_buf(),
+        };
+
+        let contents = codec::encode(vault.vault.seal()?)?;
+        fs::save_private(&vault.path, &contents)?;
+
+        Ok(vault)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Unlocks the vault, failing with `Error::WrongPassword` if `password`
+    /// is not its master password.
+    pub fn unlock(self, password: &str) -> Result<UnlockedVault> {
+        let mut vault = self.file.unlock(password)?;
+        if self.read_only {
+            vault.set_read_only();
+        }
+
+        Ok(UnlockedVault {
+            vault,
+            path: self.path,
+        })
+    }
+}
+
+impl UnlockedVault {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.vault.is_read_only()
+    }
+
+    /// Names of all entries, in alphabetical order
+    pub fn names(&self) -> Vec<String> {
+        self.vault.list()
+    }
+
+    /// Names of the entries containing `query`, ignoring case. Only names are
+    /// searched, so that no entry has to be decrypted.
+    pub fn search(&self, query: &str) -> Vec<String> {
+        let query = query.to_lowercase();
+
+        self.vault
+            .list()
+            .into_iter()
+            .filter(|name| name.to_lowercase().contains(&query))
+            .collect()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.vault.list().iter().any(|n| n == name)
+    }
+
+    /// Decrypts the entry `name`, failing with `Error::EntryNotFound` if
+    /// there is none.
+    pub fn get(&self, name: &str) -> Result<Entry> {
+        match self.vault.get(name)? {
+            Some(entry) => Ok(entry.into()),
+            None => Err(Error::EntryNotFound(name.to_string())),
+        }
+    }
+
+    /// Adds the entry `name`, failing with `Error::EntryExists` if there is
+    /// one already. Its timestamps are set to the current time.
+    pub fn add(&mut self, name: &str, entry: Entry) -> Result<()> {
+        self.vault.add(name.to_string(), entry.into())
+    }
+
+    /// Replaces the contents of the entry `name`, updating its modification
+    /// time (and its password change time, if the password changed).
+    pub fn update(&mut self, name: &str, entry: Entry) -> Result<()> {
+        self.vault.update(name, entry.into())
+    }
+
+    /// Removes the entry `name`, failing with `Error::EntryNotFound` if
+    /// there is none.
+    pub fn remove(&mut self, name: &str) -> Result<()> {
+        self.check_writable()?;
+
+        if !self.contains(name) {
+            return Err(Error::EntryNotFound(name.to_string()));
+        }
+
+        self.vault.remove(name)
+    }
+
+    /// Writes the vault, with all changes, to its data file.
+    pub fn save(&mut self) -> Result<()> {
+        self.check_writable()?;
+
+        let contents = codec::encode(self.vault.seal()?)?;
+        fs::save_atomically(&self.path, contents)?;
+        Ok(())
+    }
+
+    /// Forgets the key, giving back the locked vault. Changes not saved are
+    /// kept in it, but not written to disk.
+    pub fn lock(self) -> Result<Vault> {
+        Ok(Vault {
+            read_only: self.vault.is_read_only(),
+            file: self.vault.lock()?,
+            path: self.path,
+        })
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.is_read_only() {
+            return Err(Error::ReadOnly);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("gk-vault-{}-{}.dat", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn changes_are_saved() {
+        let path = temp_path("save");
+        let mut vault = Vault::create(&path, "master").unwrap();
+        assert!(matches!(
+            Vault::create(&path, "master"),
+            Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::AlreadyExists
+        ));
+
+        vault.add("first", Entry::new("one")).unwrap();
+        vault.add("second", Entry::new("two")).unwrap();
+        vault.save().unwrap();
+        vault.add("not saved", Entry::new("three")).unwrap();
+
+        let mut vault = Vault::open(&path).unwrap().unlock("master").unwrap();
+        assert_eq!(vault.names(), vec!["first", "second"]);
+
+        let mut entry = vault.get("first").unwrap();
+        assert_eq!(&*entry.password, "one");
+        entry.notes = String::from("notes");
+        vault.update("first", entry).unwrap();
+        vault.remove("second").unwrap();
+        vault.save().unwrap();
+
+        let vault = Vault::open(&path).unwrap().unlock("master").unwrap();
+        assert_eq!(vault.names(), vec!["first"]);
+        assert_eq!(vault.get("first").unwrap().notes, "notes");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reports_missing_entries_and_wrong_passwords() {
+        let path = temp_path("errors");
+        let mut vault = Vault::create(&path, "master").unwrap();
+
+        assert!(matches!(vault.get("none"), Err(Error::EntryNotFound(_))));
+        assert!(matches!(vault.remove("none"), Err(Error::EntryNotFound(_))));
+        vault.add("entry", Entry::new("pw")).unwrap();
+        assert!(matches!(
+            vault.add("entry", Entry::new("pw")),
+            Err(Error::EntryExists(_))
+        ));
+
+        let vault = Vault::open(&path).unwrap();
+        assert!(matches!(vault.unlock("wrong"), Err(Error::WrongPassword)));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn read_only_vaults_are_not_changed() {
+        let path = temp_path("read-only");
+        let mut vault = Vault::create(&path, "master").unwrap();
+        vault.add("entry", Entry::new("pw")).unwrap();
+        vault.save().unwrap();
+        let contents = std::fs::read(&path).unwrap();
+
+        let vault = Vault::open_read_only(&path).unwrap();
+        assert!(vault.is_read_only());
+        let mut vault = vault.unlock("master").unwrap();
+        assert_eq!(&*vault.get("entry").unwrap().password, "pw");
+
+        assert!(matches!(
+            vault.add("new", Entry::new("pw")),
+            Err(Error::ReadOnly)
+        ));
+        assert!(matches!(vault.remove("entry"), Err(Error::ReadOnly)));
+        assert!(matches!(
+            vault.update("entry", Entry::new("new")),
+            Err(Error::ReadOnly)
+        ));
+        assert!(matches!(vault.save(), Err(Error::ReadOnly)));
+        assert_eq!(std::fs::read(&path).unwrap(), contents);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn search_ignores_case() {
+        let path = temp_path("search");
+        let mut vault = Vault::create(&path, "master").unwrap();
+
+        for name in ["GitHub", "gitlab", "mail"] {
+            vault.add(name, Entry::new("pw")).unwrap();
+        }
+
+        assert_eq!(vault.search("GIT"), vec!["GitHub", "gitlab"]);
+        assert!(vault.search("none").is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -3,6 +3,11 @@ use rexpect::{process::wait::WaitStatus, spawn};
 const TIMEOUT: u64 = 1_000;
 const OTHER_FILE_PATH: &str = "./gk-test-env.dat";
 const EXE: &str = env!("CARGO_BIN_EXE_kapa");
+const AGENT_EXE: &str = env!("CARGO_BIN_EXE_kapa-agent");
+const AGENT_SOCK_ENV: &str = "GUARAKAPA_AGENT_SOCK";
+const CONFIG_PATH_ENV: &str = "GUARAKAPA_CONFIG_PATH";
+const CONFIG_FILE_PATH: &str = "./gk-test-config.toml";
+const WORK_FILE_PATH: &str = "./gk-test-work.dat";
 const PATH_ENV: &str = "GUARAKAPA_FILE_PATH";
 const MASTER_PW: &str = "test-password";
 const ENTRY_PW: &str = "entry-test-password";
@@ -18,6 +23,7 @@ macro_rules! test_fn {
 
       #[test]
       fn main_test_fn() {
+        use_test_dirs();
         std::env::remove_var(PATH_ENV);
         ensure_file_is_deleted();
         $($code)*
@@ -26,6 +32,7 @@ macro_rules! test_fn {
 
       #[test]
       fn env_test_fn() {
+        use_test_dirs();
         std::env::set_var(PATH_ENV, OTHER_FILE_PATH);
         ensure_file_is_deleted();
         $($code)*
@@ -51,8 +58,23 @@ impl<T, E> Finally<T> for Result<T, E> {
     }
 }
 
+/// Directory replacing the home and XDG directories, so that tests never
+/// touch the files of the user
+fn test_dir() -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("gk-test-cli-{}", std::process::id()))
+}
+
+fn use_test_dirs() {
+    let dir = test_dir();
+    std::env::set_var("HOME", dir.join("home"));
+    std::env::set_var("XDG_DATA_HOME", dir.join("data"));
+    std::env::set_var("XDG_CONFIG_HOME", dir.join("config"));
+    std::env::set_var("XDG_RUNTIME_DIR", dir.join("runtime"));
+}
+
 fn get_file_path() -> String {
-    guarakapa::fs::file_path()
+    use_test_dirs();
+    guarakapa::fs::file_path().unwrap()
 }
 
 fn file_exists() -> bool {
@@ -72,6 +94,8 @@ fn ensure_file_is_deleted() {
 }
 
 fn execute(params: Vec<&str>) -> rexpect::session::PtySession {
+    use_test_dirs();
+
     if params.len() > 0 {
         spawn(&format!("{} {}", EXE, params.join(" ")), Some(TIMEOUT)).unwrap()
     } else {
@@ -122,6 +146,62 @@ fn retrieve_entry(name: &str) {
     p.exp_regex("retrieved").unwrap();
 }
 
+/// Agent process listening on a temporary socket, killed when dropped
+struct TestAgent {
+    process: std::process::Child,
+    socket: std::path::PathBuf,
+}
+
+impl TestAgent {
+    fn start() -> Self {
+        let socket =
+            std::env::temp_dir().join(format!("gk-test-agent-{}.sock", std::process::id()));
+        std::env::set_var(AGENT_SOCK_ENV, &socket);
+
+        let process = std::process::Command::new(AGENT_EXE)
+            .stdout(std::process::Stdio::null())
+            .spawn()
+            .unwrap();
+
+        for _ in 0..100 {
+            if socket.exists() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        TestAgent { process, socket }
+    }
+}
+
+impl Drop for TestAgent {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+        let _ = std::fs::remove_file(&self.socket);
+        std::env::remove_var(AGENT_SOCK_ENV);
+    }
+}
+
+/// Uses a temporary config file, deleted when dropped along with the data
+/// file of the `work` vault
+struct TestConfig;
+
+impl TestConfig {
+    fn new() -> Self {
+        std::env::set_var(CONFIG_PATH_ENV, CONFIG_FILE_PATH);
+        TestConfig
+    }
+}
+
+impl Drop for TestConfig {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(CONFIG_FILE_PATH);
+        let _ = std::fs::remove_file(WORK_FILE_PATH);
+        std::env::remove_var(CONFIG_PATH_ENV);
+    }
+}
+
 #[test]
 fn can_execute() {
     let p = execute(vec!["-v"]);
@@ -168,6 +248,62 @@ fn not_using_env_var_works() {
     ensure_file_is_deleted();
 }
 
+#[test]
+fn moves_data_file_from_old_location() {
+    ensure_file_is_deleted();
+    std::env::remove_var(PATH_ENV);
+    create_file();
+
+    let legacy = test_dir().join("home/.config/guarakapa/gk.dat");
+    std::fs::create_dir_all(legacy.parent().unwrap()).unwrap();
+    std::fs::rename(get_file_path(), &legacy).unwrap();
+    // as before the first run of a version using the data directory
+    let data_dir = std::path::Path::new(&get_file_path())
+        .parent()
+        .unwrap()
+        .to_path_buf();
+    std::fs::remove_dir_all(&data_dir).unwrap();
+
+    let mut p = execute(vec!["ls"]);
+    p.exp_regex("Moved .*gk.dat").unwrap_or_fail();
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("no entries yet").unwrap_or_fail();
+    assert!(!legacy.exists());
+
+    // only once: files left in the old location afterwards stay there
+    std::fs::rename(get_file_path(), &legacy).unwrap();
+    let mut p = execute(vec!["ls"]);
+    p.exp_regex("not found").unwrap_or_fail();
+    assert!(legacy.exists());
+    std::fs::remove_file(&legacy).unwrap();
+    ensure_file_is_deleted();
+}
+
+#[test]
+fn read_only_mode_does_not_move_legacy_files() {
+    ensure_file_is_deleted();
+    std::env::remove_var(PATH_ENV);
+    create_file();
+
+    let legacy = test_dir().join("home/.config/guarakapa/gk.dat");
+    std::fs::create_dir_all(legacy.parent().unwrap()).unwrap();
+    std::fs::rename(get_file_path(), &legacy).unwrap();
+    let data_dir = std::path::Path::new(&get_file_path())
+        .parent()
+        .unwrap()
+        .to_path_buf();
+    std::fs::remove_dir_all(&data_dir).unwrap();
+
+    let mut p = execute(vec!["--read-only", "path"]);
+    p.exp_regex("Password file not found").unwrap();
+    p.exp_eof().unwrap();
+    assert!(legacy.exists());
+    assert!(!data_dir.exists());
+
+    std::fs::remove_file(&legacy).unwrap();
+    ensure_file_is_deleted();
+}
+
 test_fn! { reports_file_missing,
     let args = vec!["ls", "add myentry", "rm myentry", "entry"];
 
@@ -240,6 +376,870 @@ test_fn! { can_list_two_entries,
     p.exp_regex("entry2").unwrap();
 }
 
+test_fn! { can_list_entries_with_timestamps,
+    create_file();
+    add_entry("entry1");
+    let mut p = execute(vec!["ls", "--long", "--sort", "modified"]);
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("MODIFIED").unwrap();
+    p.exp_regex(r"entry1 +\d{4}-\d{2}-\d{2}").unwrap();
+}
+
+test_fn! { audit_reports_old_passwords,
+    create_file();
+    add_entry("entry1");
+    let mut p = execute(vec!["audit", "--days", "0"]);
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("found").unwrap();
+    p.exp_regex("entry1: password not changed in 0 days").unwrap();
+}
+
+test_fn! { audit_reports_reused_passwords_as_json,
+    create_file();
+    add_entry("entry1");
+    add_entry("entry2");
+    let mut p = execute(vec!["audit", "--json"]);
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex(r#""kind": "reused""#).unwrap();
+    p.exp_regex(r#""entry2""#).unwrap();
+}
+
+test_fn! { breach_check_finds_compromised_entries,
+    create_file();
+    add_entry("entry1");
+
+    let db_path = "./gk-test-breaches.txt";
+    let mut lines = [
+        format!("{}:42", guarakapa::breach::sha1_hex(ENTRY_PW)),
+        format!("{}:7", guarakapa::breach::sha1_hex(WRONG_PW)),
+    ];
+    lines.sort();
+    std::fs::write(db_path, lines.join("\n")).unwrap();
+
+    let mut p = execute(vec!["breach-check", "--db", db_path]);
+    p.send_line(MASTER_PW).unwrap();
+    let result = p.exp_regex(r"entry1 \(seen 42 times");
+    std::fs::remove_file(db_path).unwrap();
+    result.unwrap_or_fail();
+}
+
+test_fn! { agent_keeps_file_unlocked,
+    create_file();
+    add_entry("entry1");
+    let _agent = TestAgent::start();
+
+    let mut p = execute(vec!["unlock"]);
+    p.exp_regex("password").unwrap_or_fail();
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("unlocked").unwrap_or_fail();
+
+    // no password is asked while the agent has the key
+    let mut p = execute(vec!["ls"]);
+    p.exp_regex("Total entries").unwrap_or_fail();
+    p.exp_regex("entry1").unwrap_or_fail();
+
+    let mut p = execute(vec!["lock"]);
+    p.exp_regex("locked").unwrap_or_fail();
+
+    let mut p = execute(vec!["ls"]);
+    p.exp_regex("password").unwrap_or_fail();
+}
+
+/// Runs a command with malformed arguments, which must show the help text
+/// and fail
+fn expect_usage_error(params: Vec<&str>) {
+    let mut p = execute(params);
+    p.exp_regex("General usage").unwrap_or_fail();
+    match p.process.wait() {
+        Ok(WaitStatus::Exited(_, 0)) | Err(_) => panic!("process exited with zero status"),
+        _ => (),
+    }
+}
+
+#[test]
+fn rejects_malformed_vaults_commands() {
+    let _config = TestConfig::new();
+    expect_usage_error(vec!["vaults", "list"]);
+    expect_usage_error(vec!["vaults", "add", "work"]);
+}
+
+#[test]
+fn rejects_malformed_config_commands() {
+    let _config = TestConfig::new();
+    expect_usage_error(vec!["config", "list"]);
+    expect_usage_error(vec!["config", "set", "clipboard.timeout"]);
+}
+
+#[test]
+fn rejects_malformed_diff_options() {
+    expect_usage_error(vec!["diff", "old.dat", "new.dat", "--secrets"]);
+}
+
+#[test]
+fn rejects_malformed_options() {
+    expect_usage_error(vec!["-o", "clipboard.timeout"]);
+}
+
+test_fn! { rejects_malformed_ls_options,
+    create_file();
+    expect_usage_error(vec!["ls", "--sort", "size"]);
+    expect_usage_error(vec!["ls", "--short"]);
+}
+
+test_fn! { rejects_malformed_audit_options,
+    create_file();
+    expect_usage_error(vec!["audit", "--days", "many"]);
+}
+
+test_fn! { rejects_malformed_add_options,
+    create_file();
+    expect_usage_error(vec!["add", "entry1", "--group"]);
+}
+
+test_fn! { rejects_unknown_padding_modes,
+    create_file();
+    expect_usage_error(vec!["padding", "full"]);
+}
+
+test_fn! { rejects_malformed_escrow_options,
+    create_file();
+    expect_usage_error(vec!["escrow", "split", "-k", "2"]);
+}
+
+test_fn! { rejects_unknown_commands,
+    create_file();
+    expect_usage_error(vec!["recovery", "show", "all"]);
+}
+
+test_fn! { can_copy_entries_between_vaults,
+    let _config = TestConfig::new();
+    create_file();
+    add_entry("entry1");
+
+    let mut p = execute(vec!["vaults", "add", "work", WORK_FILE_PATH]);
+    p.exp_regex("updated").unwrap_or_fail();
+
+    let mut p = execute(vec!["--vault", "work"]);
+    p.exp_regex("password").unwrap_or_fail();
+    p.send_line(WRONG_PW).unwrap();
+    p.exp_regex("repeat").unwrap_or_fail();
+    p.send_line(WRONG_PW).unwrap();
+    p.exp_regex("created").unwrap_or_fail();
+
+    let mut p = execute(vec!["vaults"]);
+    p.exp_regex(r"\* default .* \(version").unwrap_or_fail();
+    p.exp_regex(r"work .*gk-test-work\.dat +\(version").unwrap_or_fail();
+
+    let source = test_dir().join("cp_test");
+    std::fs::create_dir_all(test_dir()).unwrap();
+    std::fs::write(&source, "attached").unwrap();
+    let mut p = execute(vec!["attach", "entry1", source.to_str().unwrap()]);
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("attached").unwrap_or_fail();
+    std::fs::remove_file(&source).unwrap();
+
+    let mut p = execute(vec!["cp", "entry1", "work", "copied"]);
+    p.exp_regex("master password").unwrap_or_fail();
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("vault `work`").unwrap_or_fail();
+    p.send_line(WRONG_PW).unwrap();
+    p.exp_regex("copied to vault").unwrap_or_fail();
+
+    let mut p = execute(vec!["--vault", "work", "ls"]);
+    p.send_line(WRONG_PW).unwrap();
+    p.exp_regex("copied").unwrap_or_fail();
+
+    let mut p = execute(vec!["--vault", "work", "attachments", "copied"]);
+    p.send_line(WRONG_PW).unwrap();
+    p.exp_regex("cp_test  8 bytes").unwrap_or_fail();
+
+    let mut p = execute(vec!["--vault", "missing", "ls"]);
+    p.exp_regex("Vault `missing` not found").unwrap_or_fail();
+}
+
+test_fn! { can_change_settings,
+    let _config = TestConfig::new();
+
+    let mut p = execute(vec!["config", "set", "clipboard.timeout", "5"]);
+    p.exp_regex("set to").unwrap_or_fail();
+
+    let mut p = execute(vec!["config", "get", "clipboard.timeout"]);
+    p.exp_regex("5").unwrap_or_fail();
+
+    let mut p = execute(vec!["config", "set", "output", "yaml"]);
+    p.exp_regex("Invalid").unwrap_or_fail();
+
+    let mut p = execute(vec!["config", "set", "no.such.key", "1"]);
+    p.exp_regex("Unknown").unwrap_or_fail();
+
+    let mut p = execute(vec!["-o", "clipboard.timeout=9", "config", "show"]);
+    p.exp_regex("timeout = 9").unwrap_or_fail();
+
+    let mut p = execute(vec!["config", "get", "clipboard.timeout"]);
+    p.exp_regex("5").unwrap_or_fail();
+}
+
+test_fn! { settings_change_prompts_and_clipboard,
+    let _config = TestConfig::new();
+    create_file();
+
+    let mut p = execute(vec!["config", "set", "add.fields", r#"'["user"]'"#]);
+    p.exp_regex("set to").unwrap_or_fail();
+    let mut p = execute(vec!["config", "set", "clipboard.backend", "none"]);
+    p.exp_regex("set to").unwrap_or_fail();
+
+    let mut p = execute(vec!["add", "entry1", "--generate"]);
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("user name").unwrap_or_fail();
+    p.send_line("someone").unwrap();
+    p.exp_regex("added").unwrap_or_fail();
+
+    let mut p = execute(vec!["entry1"]);
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("someone").unwrap_or_fail();
+    p.exp_regex("not copied").unwrap_or_fail();
+}
+
+test_fn! { can_generate_passwords,
+    let _config = TestConfig::new();
+
+    let mut p = execute(vec!["-o", "generator.length=32", "gen"]);
+    p.exp_regex(r"\S{32}").unwrap_or_fail();
+
+    let mut p = execute(vec!["-o", "generator.length=2", "gen"]);
+    p.exp_regex("length must be").unwrap_or_fail();
+}
+
+test_fn! { usage_is_only_tracked_if_enabled,
+    create_file();
+    add_entry("entry1");
+    let contents = std::fs::read(get_file_path()).unwrap();
+
+    let mut p = execute(vec!["-o", "clipboard.backend=none", "entry1"]);
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("retrieved").unwrap_or_fail();
+    p.exp_eof().unwrap();
+    assert_eq!(std::fs::read(get_file_path()).unwrap(), contents);
+
+    let mut p = execute(vec![
+        "-o",
+        "clipboard.backend=none",
+        "-o",
+        "track_usage=true",
+        "entry1",
+    ]);
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("retrieved").unwrap_or_fail();
+    p.exp_eof().unwrap();
+
+    let mut p = execute(vec!["ls", "--long"]);
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex(r"entry1( +\d{4}-\d{2}-\d{2} \d{2}:\d{2}){4}").unwrap_or_fail();
+}
+
+test_fn! { read_only_mode_does_not_write,
+    create_file();
+    add_entry("entry1");
+    let contents = std::fs::read(get_file_path()).unwrap();
+
+    let mut p = execute(vec![
+        "--read-only",
+        "-o",
+        "clipboard.backend=none",
+        "-o",
+        "track_usage=true",
+        "entry1",
+    ]);
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("retrieved").unwrap_or_fail();
+
+    // the vault refuses the changes
+    for params in [
+        vec!["add", "entry2"],
+        vec!["rm", "entry1"],
+        vec!["padding", "entries"],
+        vec!["recovery", "create"],
+    ] {
+        let mut p = execute([vec!["--read-only"], params].concat());
+        p.exp_regex("password").unwrap_or_fail();
+        p.send_line(MASTER_PW).unwrap();
+        p.exp_regex("read-only").unwrap_or_fail();
+    }
+
+    let mut p = execute(vec!["--read-only", "sync"]);
+    p.exp_regex("read-only mode").unwrap_or_fail();
+
+    assert_eq!(std::fs::read(get_file_path()).unwrap(), contents);
+}
+
+#[test]
+fn can_merge_another_copy() {
+    use_test_dirs();
+    ensure_file_is_deleted();
+    std::env::remove_var(PATH_ENV);
+
+    let dir = test_dir().join("merge");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let (base, other) = (dir.join("base.dat"), dir.join("other.dat"));
+
+    create_file();
+    add_entry("entry1");
+    std::fs::copy(get_file_path(), &base).unwrap();
+    std::fs::copy(get_file_path(), &other).unwrap();
+    add_entry("entry2");
+
+    std::env::set_var(PATH_ENV, &other);
+    remove_entry("entry1");
+    add_entry("entry3");
+    std::env::remove_var(PATH_ENV);
+
+    let mut p = execute(vec![
+        "merge",
+        other.to_str().unwrap(),
+        "--base",
+        base.to_str().unwrap(),
+    ]);
+    p.exp_regex("password").unwrap();
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("1 added, 0 updated, 1 removed").unwrap();
+
+    let mut p = execute(vec!["ls"]);
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex(r"Total entries \(2\):\s+entry2\s+entry3")
+        .unwrap();
+
+    let mut p = execute(vec!["merge", other.to_str().unwrap()]);
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("Nothing to merge").unwrap();
+
+    ensure_file_is_deleted();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn can_show_differences() {
+    use_test_dirs();
+    ensure_file_is_deleted();
+    std::env::remove_var(PATH_ENV);
+
+    let dir = test_dir().join("diff");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let old = dir.join("old.dat");
+    let (old, new) = (old.to_str().unwrap(), get_file_path());
+
+    create_file();
+    add_entry("entry1");
+    add_entry("entry2");
+    std::fs::copy(&new, old).unwrap();
+    remove_entry("entry1");
+    add_entry("entry3");
+
+    let mut p = execute(vec!["diff", old, &new]);
+    p.exp_regex("password").unwrap();
+    p.send_line(MASTER_PW).unwrap();
+    // entries added by the tests have the same contents
+    p.exp_regex(r"renamed\s+entry1 -> entry3").unwrap();
+
+    let mut p = execute(vec!["diff", old, &new, "--json"]);
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex(r#""kind": "renamed",\s+"from": "entry1",\s+"to": "entry3""#)
+        .unwrap();
+
+    let mut p = execute(vec!["diff", old, old]);
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("No differences").unwrap();
+
+    ensure_file_is_deleted();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Shares the `work` vault between the user and a second user, whose
+/// personal vault is in another file.
+#[test]
+fn members_share_team_vaults() {
+    use_test_dirs();
+    ensure_file_is_deleted();
+    std::env::remove_var(PATH_ENV);
+    let _config = TestConfig::new();
+
+    let dir = test_dir().join("team");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let (bob_path, bob_key) = (dir.join("bob.dat"), dir.join("bob.pem"));
+
+    create_file();
+    let mut p = execute(vec!["member", "key"]);
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("BEGIN PUBLIC KEY").unwrap();
+
+    execute(vec!["vaults", "add", "work", WORK_FILE_PATH])
+        .exp_regex("updated")
+        .unwrap();
+    let mut p = execute(vec!["--vault", "work", "member", "add", "alice"]);
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("Team vault created").unwrap();
+
+    let mut p = execute(vec!["--vault", "work", "add", "shared", "-g"]);
+    p.send_line(MASTER_PW).unwrap();
+    for _ in 0..4 {
+        p.exp_regex("ENTER").unwrap();
+        p.send_line("").unwrap();
+    }
+    p.exp_regex("added").unwrap();
+
+    std::env::set_var(PATH_ENV, &bob_path);
+    create_file();
+    let mut p = execute(vec!["member", "key"]);
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_string("-----BEGIN PUBLIC KEY-----").unwrap();
+    let body = p.exp_string("-----END PUBLIC KEY-----").unwrap();
+    let pem = format!(
+        "-----BEGIN PUBLIC KEY-----{}-----END PUBLIC KEY-----\n",
+        body
+    );
+    std::fs::write(&bob_key, pem.replace('\r', "")).unwrap();
+
+    let mut p = execute(vec!["--vault", "work", "ls"]);
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("not a member").unwrap();
+
+    std::env::remove_var(PATH_ENV);
+    let mut p = execute(vec![
+        "--vault",
+        "work",
+        "member",
+        "add",
+        "bob",
+        bob_key.to_str().unwrap(),
+    ]);
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("Member `bob` added").unwrap();
+
+    let mut p = execute(vec!["--vault", "work", "member", "list"]);
+    p.exp_regex("alice").unwrap();
+    p.exp_regex("bob").unwrap();
+
+    std::env::set_var(PATH_ENV, &bob_path);
+    let mut p = execute(vec!["--vault", "work", "ls"]);
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("shared").unwrap();
+
+    // entries of a group are hidden from the members not in it
+    std::env::remove_var(PATH_ENV);
+    let mut p = execute(vec!["--vault", "work", "group", "create", "ops"]);
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("Group `ops` created").unwrap();
+    let mut p = execute(vec![
+        "--vault", "work", "add", "server", "--group", "ops", "-g",
+    ]);
+    p.send_line(MASTER_PW).unwrap();
+    for _ in 0..4 {
+        p.exp_regex("ENTER").unwrap();
+        p.send_line("").unwrap();
+    }
+    p.exp_regex("added").unwrap();
+    let mut p = execute(vec!["--vault", "work", "group", "list"]);
+    p.exp_regex("ops  alice").unwrap();
+
+    std::env::set_var(PATH_ENV, &bob_path);
+    let mut p = execute(vec!["--vault", "work", "ls"]);
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("shared").unwrap();
+    assert!(!p.exp_eof().unwrap().contains("server"));
+    let mut p = execute(vec!["--vault", "work", "get", "server"]);
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("belongs to group `ops`").unwrap();
+
+    std::env::remove_var(PATH_ENV);
+    let mut p = execute(vec!["--vault", "work", "group", "add", "ops", "bob"]);
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("added to group").unwrap();
+
+    std::env::set_var(PATH_ENV, &bob_path);
+    let mut p = execute(vec!["--vault", "work", "ls"]);
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("server").unwrap();
+
+    std::env::remove_var(PATH_ENV);
+    let mut p = execute(vec!["--vault", "work", "member", "remove", "bob"]);
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("key changed").unwrap();
+
+    std::env::set_var(PATH_ENV, &bob_path);
+    let mut p = execute(vec!["--vault", "work", "ls"]);
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("not a member").unwrap();
+
+    std::env::remove_var(PATH_ENV);
+    ensure_file_is_deleted();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn password_alone_fails_with_key_file() {
+    use_test_dirs();
+    ensure_file_is_deleted();
+    std::env::remove_var(PATH_ENV);
+
+    let dir = test_dir().join("keyfile");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let (key_file, other_file) = (dir.join("key"), dir.join("other"));
+    let (key_file, other_file) = (key_file.to_str().unwrap(), other_file.to_str().unwrap());
+    std::fs::write(other_file, "not the key").unwrap();
+
+    create_file();
+    add_entry("entry1");
+    let mut p = execute(vec!["keyfile", "create", key_file]);
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("Key file created").unwrap();
+
+    let mut p = execute(vec!["ls"]);
+    p.exp_regex("key file is required").unwrap();
+
+    let mut p = execute(vec!["--keyfile", other_file, "ls"]);
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("does not match").unwrap();
+
+    let mut p = execute(vec!["--keyfile", key_file, "ls"]);
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("entry1").unwrap();
+
+    ensure_file_is_deleted();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+fn file_challenge() -> Vec<u8> {
+    let contents = std::fs::read(get_file_path()).unwrap();
+    let file: guarakapa::fman::File = guarakapa::codec::decode(contents.as_slice()).unwrap();
+    file.challenge().unwrap().to_vec()
+}
+
+#[test]
+fn challenge_changes_on_every_save() {
+    use_test_dirs();
+    ensure_file_is_deleted();
+    std::env::remove_var(PATH_ENV);
+
+    let script = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/hmac-response.sh");
+    std::env::set_var("GUARAKAPA_CHALLENGE_COMMAND", script);
+
+    create_file();
+    add_entry("entry1");
+    let mut p = execute(vec!["challenge", "enable"]);
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("Challenge set").unwrap();
+    let challenge = file_challenge();
+
+    add_entry("entry2");
+    assert_ne!(file_challenge(), challenge);
+
+    let mut p = execute(vec!["-o", "challenge.command=cat", "ls"]);
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("Response to the challenge does not match")
+        .unwrap();
+
+    let mut p = execute(vec!["-o", "challenge.command=false", "ls"]);
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("failed").unwrap();
+
+    let mut p = execute(vec!["challenge", "disable"]);
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("Challenge removed").unwrap();
+
+    std::env::remove_var("GUARAKAPA_CHALLENGE_COMMAND");
+    let mut p = execute(vec!["ls"]);
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("entry1").unwrap();
+    p.exp_regex("entry2").unwrap();
+
+    ensure_file_is_deleted();
+}
+
+#[test]
+fn recovery_key_resets_master_password() {
+    use_test_dirs();
+    ensure_file_is_deleted();
+    std::env::remove_var(PATH_ENV);
+
+    create_file();
+    add_entry("entry1");
+    let mut p = execute(vec!["recovery", "create"]);
+    p.send_line(MASTER_PW).unwrap();
+    let (_, id) = p.exp_regex(r"slot [0-9a-f]{8}").unwrap();
+    let (_, recovery_key) = p.exp_regex(r"([A-Z2-7]{4}-){11}[A-Z2-7]{4}").unwrap();
+    let id = id.trim_start_matches("slot ");
+
+    let mut p = execute(vec!["recovery", "use"]);
+    p.send_line(&recovery_key.replace('-', "").to_lowercase())
+        .unwrap();
+    p.send_line(WRONG_PW).unwrap();
+    p.send_line(WRONG_PW).unwrap();
+    p.exp_regex("Master password changed").unwrap();
+
+    let mut p = execute(vec!["ls"]);
+    p.send_line(WRONG_PW).unwrap();
+    p.exp_regex("entry1").unwrap();
+
+    execute(vec!["recovery", "list"]).exp_regex(id).unwrap();
+    let mut p = execute(vec!["recovery", "revoke", id]);
+    p.send_line(WRONG_PW).unwrap();
+    p.exp_regex("revoked").unwrap();
+
+    let mut p = execute(vec!["recovery", "use"]);
+    p.send_line(&recovery_key).unwrap();
+    p.exp_regex("does not open this vault").unwrap();
+
+    ensure_file_is_deleted();
+}
+
+#[test]
+fn escrow_shares_reset_master_password() {
+    use_test_dirs();
+    ensure_file_is_deleted();
+    std::env::remove_var(PATH_ENV);
+
+    create_file();
+    add_entry("entry1");
+    let mut p = execute(vec!["escrow", "split", "-k", "2", "-n", "3"]);
+    p.send_line(MASTER_PW).unwrap();
+    let shares: Vec<String> = (0..3)
+        .map(|_| p.exp_regex(r"([A-Z2-7]{4}-){13}[A-Z2-7]{4}").unwrap().1)
+        .collect();
+
+    let mut p = execute(vec!["escrow", "combine"]);
+    p.send_line(&shares[2]).unwrap();
+    p.send_line(&shares[2]).unwrap();
+    p.exp_regex("given twice").unwrap();
+
+    let mut p = execute(vec!["escrow", "combine"]);
+    p.send_line(&shares[2]).unwrap();
+    p.send_line(&shares[0]).unwrap();
+    p.send_line(WRONG_PW).unwrap();
+    p.send_line(WRONG_PW).unwrap();
+    p.exp_regex("Master password changed").unwrap();
+
+    let mut p = execute(vec!["ls"]);
+    p.send_line(WRONG_PW).unwrap();
+    p.exp_regex("entry1").unwrap();
+
+    ensure_file_is_deleted();
+}
+
+#[test]
+fn decoy_password_opens_other_entries() {
+    use_test_dirs();
+    ensure_file_is_deleted();
+    std::env::remove_var(PATH_ENV);
+
+    let mut p = execute(vec!["--decoy"]);
+    p.send_line(MASTER_PW).unwrap();
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("decoy password").unwrap();
+    p.send_line(WRONG_PW).unwrap();
+    p.send_line(WRONG_PW).unwrap();
+    p.exp_regex("created").unwrap();
+
+    add_entry("entry1");
+    let mut p = execute(vec!["add", "decoy1"]);
+    p.send_line(WRONG_PW).unwrap();
+    for _ in 0..4 {
+        p.exp_regex("blank").unwrap();
+        p.send_line("").unwrap();
+    }
+    p.exp_regex("new password").unwrap();
+    p.send_line(ENTRY_PW).unwrap();
+    p.exp_regex("added").unwrap();
+
+    let mut p = execute(vec!["ls"]);
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("entry1").unwrap();
+    assert!(!p.exp_eof().unwrap().contains("decoy1"));
+    let mut p = execute(vec!["ls"]);
+    p.send_line(WRONG_PW).unwrap();
+    p.exp_regex("decoy1").unwrap();
+    assert!(!p.exp_eof().unwrap().contains("entry1"));
+
+    let mut p = execute(vec!["recovery", "create"]);
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("only have a master password").unwrap();
+
+    // the same kind of file, without a decoy
+    ensure_file_is_deleted();
+    let mut p = execute(vec!["--decoy"]);
+    p.send_line(MASTER_PW).unwrap();
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("decoy password").unwrap();
+    p.send_line("").unwrap();
+    p.exp_regex("created").unwrap();
+
+    add_entry("entry1");
+    let mut p = execute(vec!["ls"]);
+    p.send_line(WRONG_PW).unwrap();
+    p.exp_regex("does not match").unwrap();
+
+    ensure_file_is_deleted();
+}
+
+#[test]
+fn padding_keeps_entries_readable() {
+    use_test_dirs();
+    ensure_file_is_deleted();
+    std::env::remove_var(PATH_ENV);
+
+    create_file();
+    add_entry("entry1");
+    let size = std::fs::metadata(get_file_path()).unwrap().len();
+
+    let mut p = execute(vec!["padding", "entries"]);
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("Padding set to `entries`").unwrap();
+    assert!(std::fs::metadata(get_file_path()).unwrap().len() > size);
+
+    add_entry("entry2");
+    let mut p = execute(vec!["padding", "table"]);
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("Padding set to `table`").unwrap();
+
+    let mut p = execute(vec!["padding"]);
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("table").unwrap();
+    let mut p = execute(vec!["ls"]);
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("entry1").unwrap();
+    p.exp_regex("entry2").unwrap();
+
+    ensure_file_is_deleted();
+}
+
+#[test]
+fn attachments_are_extracted_privately() {
+    use std::os::unix::fs::PermissionsExt;
+
+    use_test_dirs();
+    ensure_file_is_deleted();
+    std::env::remove_var(PATH_ENV);
+
+    create_file();
+    add_entry("entry1");
+    let dir = std::path::Path::new(&get_file_path())
+        .parent()
+        .unwrap()
+        .to_path_buf();
+    let source = dir.join("id_test");
+    let out = dir.join("id_test.out");
+    std::fs::write(&source, "private key").unwrap();
+    let _ = std::fs::remove_file(&out);
+
+    let mut p = execute(vec!["attach", "entry1", source.to_str().unwrap()]);
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("File `id_test` attached").unwrap();
+
+    let mut p = execute(vec!["attachments", "entry1"]);
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("id_test  11 bytes").unwrap();
+
+    let mut p = execute(vec![
+        "extract",
+        "entry1",
+        "id_test",
+        "--out",
+        out.to_str().unwrap(),
+    ]);
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("written").unwrap();
+    assert_eq!(std::fs::read_to_string(&out).unwrap(), "private key");
+    let mode = std::fs::metadata(&out).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    let mut p = execute(vec!["detach", "entry1", "id_test"]);
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("removed").unwrap();
+
+    std::fs::remove_file(&source).unwrap();
+    std::fs::remove_file(&out).unwrap();
+    ensure_file_is_deleted();
+}
+
+#[test]
+fn backspace_removes_whole_characters() {
+    use_test_dirs();
+    ensure_file_is_deleted();
+    std::env::remove_var(PATH_ENV);
+
+    let mut p = execute(Vec::new());
+    p.exp_regex("password").unwrap();
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("repeat").unwrap();
+    p.send_line(&format!("{}€\x7f", MASTER_PW)).unwrap();
+    p.exp_regex("created").unwrap();
+
+    let mut p = execute(vec!["ls"]);
+    p.exp_regex("password").unwrap();
+    p.send_line(&format!("{}ü\x7fa\x7f", MASTER_PW)).unwrap();
+    p.exp_regex("no entries yet").unwrap();
+
+    ensure_file_is_deleted();
+}
+
+/// Syncs two data files (the default one, and one in another directory)
+/// through a bare repository.
+#[test]
+fn can_sync_through_remote_repository() {
+    use_test_dirs();
+    ensure_file_is_deleted();
+    std::env::remove_var(PATH_ENV);
+
+    let dir = test_dir().join("sync");
+    let _ = std::fs::remove_dir_all(&dir);
+    let remote = dir.join("remote.git");
+    std::process::Command::new("git")
+        .args(["init", "-q", "--bare", "-b", "main"])
+        .arg(&remote)
+        .status()
+        .unwrap();
+    std::env::set_var("GUARAKAPA_SYNC_REMOTE", &remote);
+    for var in ["GIT_AUTHOR", "GIT_COMMITTER"] {
+        std::env::set_var(format!("{}_NAME", var), "test");
+        std::env::set_var(format!("{}_EMAIL", var), "test@example.com");
+    }
+    let other_path = dir.join("other").join("gk.dat");
+
+    create_file();
+    add_entry("entry1");
+    execute(vec!["sync"]).exp_regex("pushed").unwrap();
+
+    std::env::set_var(PATH_ENV, &other_path);
+    execute(vec!["sync"]).exp_regex("pulled").unwrap();
+    add_entry("entry2");
+    execute(vec!["sync"]).exp_regex("pushed").unwrap();
+
+    std::env::remove_var(PATH_ENV);
+    add_entry("entry3");
+    let mut p = execute(vec!["sync"]);
+    p.exp_regex("password").unwrap();
+    p.send_line(MASTER_PW).unwrap();
+    p.exp_regex("1 added, 0 updated, 0 removed").unwrap();
+    p.exp_regex("merged and pushed").unwrap();
+    retrieve_entry("entry2");
+
+    std::env::set_var(PATH_ENV, &other_path);
+    execute(vec!["sync"]).exp_regex("pulled").unwrap();
+    retrieve_entry("entry3");
+
+    std::env::remove_var(PATH_ENV);
+    std::env::remove_var("GUARAKAPA_SYNC_REMOTE");
+    ensure_file_is_deleted();
+    let data_dir = std::path::Path::new(&get_file_path())
+        .parent()
+        .unwrap()
+        .to_path_buf();
+    std::fs::remove_dir_all(data_dir.join(".git")).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
 test_fn! { can_retrieve_entry,
     create_file();
     add_entry("entry1");
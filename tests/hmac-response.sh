@@ -0,0 +1,8 @@
+#!/bin/sh
+# Answers the challenge of a vault like the HMAC-SHA1 slot of a hardware key,
+# with a fixed key, for the tests: reads the challenge in hexadecimal from the
+# standard input and writes the HMAC in hexadecimal.
+read -r challenge
+printf '%s' "$challenge" | xxd -r -p |
+    openssl dgst -sha1 -mac HMAC -macopt hexkey:000102030405060708090a0b0c0d0e0f10111213 -r |
+    cut -d ' ' -f 1
@@ -3,8 +3,17 @@ use std::io::ErrorKind;
 
 const PASSWORD: &str = "dummy-pass";
 
+/// Keeps the data file in a temporary directory instead of the user's data
+/// directory
+fn use_test_dir() {
+    let dir = std::env::temp_dir().join(format!("gk-test-integration-{}", std::process::id()));
+    std::env::set_var("XDG_DATA_HOME", &dir);
+    std::env::remove_var(fs::PATH_ENV);
+}
+
 fn delete_file() {
-    let filepath = fs::file_path();
+    use_test_dir();
+    let filepath = fs::file_path().unwrap();
     if let Err(e) = std::fs::remove_file(filepath) {
         match e.kind() {
             ErrorKind::NotFound => (),
@@ -21,13 +30,14 @@ fn get_dummy_entry() -> fman::OpenEntry {
         user: String::from("user"),
         email: String::from("email"),
         notes: String::from("notes"),
-        pw: String::from("password"),
+        pw: "password".into(),
+        ..Default::default()
     }
 }
 
 fn create_file() -> Vec<u8> {
     let pw = String::from(PASSWORD);
-    let file = fman::File::try_new(pw).unwrap();
+    let file = fman::File::try_new(&pw).unwrap();
     let file_contents = codec::encode(&file).unwrap();
     let original_content = file_contents.clone();
     fs::save(file_contents).unwrap();
@@ -43,23 +53,27 @@ fn read_file() -> fman::File {
     codec::decode(contents.as_slice()).unwrap()
 }
 
-fn add_dummy_entry(file: &mut fman::File, pw: String, entry_name: &str) -> Vec<u8> {
-    let entry = get_dummy_entry();
-    file.add_entry(pw, entry_name.to_string(), entry).unwrap();
-    let file_contents = codec::encode(file).unwrap();
+fn unlock_file() -> fman::UnlockedVault {
+    read_file().unlock(PASSWORD).unwrap()
+}
+
+fn save(vault: &mut fman::UnlockedVault) -> Vec<u8> {
+    let file_contents = codec::encode(vault.seal().unwrap()).unwrap();
     let new_content = file_contents.clone();
     fs::save(file_contents).unwrap();
 
     return new_content;
 }
 
-fn remove_entry(file: &mut fman::File, pw: String, entry_name: &str) -> Vec<u8> {
-    file.remove_entry(pw, entry_name).unwrap();
-    let file_contents = codec::encode(file).unwrap();
-    let new_content = file_contents.clone();
-    fs::save(file_contents).unwrap();
+fn add_dummy_entry(vault: &mut fman::UnlockedVault, entry_name: &str) -> Vec<u8> {
+    let entry = get_dummy_entry();
+    vault.add(entry_name.to_string(), entry).unwrap();
+    save(vault)
+}
 
-    return new_content;
+fn remove_entry(vault: &mut fman::UnlockedVault, entry_name: &str) -> Vec<u8> {
+    vault.remove(entry_name).unwrap();
+    save(vault)
 }
 
 #[test]
@@ -70,20 +84,23 @@ fn can_create_file() {
 }
 
 #[test]
-fn can_add_entry() {
+fn cannot_unlock_with_wrong_password() {
     delete_file();
+    create_file();
 
-    let original_content = create_file();
-    let mut file = read_file();
+    assert!(read_file().unlock("wrong-pass").is_err());
 
-    let pw = String::from(PASSWORD);
-    let pw_hash = crypto::hash(vec![pw.as_bytes(), &file.head.salt[..]]);
+    delete_file();
+}
 
-    if pw_hash != file.head.pw_hash {
-        panic!("Wrong password hash.");
-    }
+#[test]
+fn can_add_entry() {
+    delete_file();
+
+    let original_content = create_file();
+    let mut vault = unlock_file();
 
-    let new_content = add_dummy_entry(&mut file, pw, "entry1");
+    let new_content = add_dummy_entry(&mut vault, "entry1");
     assert_ne!(original_content, new_content);
     assert!(new_content.len() > original_content.len());
 
@@ -95,21 +112,13 @@ fn can_add_several_entries() {
     delete_file();
 
     let original_content = create_file();
-    let mut file = read_file();
-
-    let pw = String::from(PASSWORD);
-    let pw_hash = crypto::hash(vec![pw.as_bytes(), &file.head.salt[..]]);
-
-    if pw_hash != file.head.pw_hash {
-        panic!("Wrong password hash.");
-    }
+    let mut vault = unlock_file();
 
     let mut old_content = original_content;
 
     for i in 0..5 {
-        let pw = String::from(PASSWORD);
         let entry_name = format!("entry{}", i);
-        let new_content = add_dummy_entry(&mut file, pw, &entry_name);
+        let new_content = add_dummy_entry(&mut vault, &entry_name);
 
         assert_ne!(new_content, old_content);
         assert!(new_content.len() > old_content.len());
@@ -125,30 +134,21 @@ fn can_delete_entry() {
     delete_file();
 
     let original_content = create_file();
-    let mut file = read_file();
-
-    let pw = String::from(PASSWORD);
-    let pw_hash = crypto::hash(vec![pw.as_bytes(), &file.head.salt[..]]);
-
-    if pw_hash != file.head.pw_hash {
-        panic!("Wrong password hash.");
-    }
+    let mut vault = unlock_file();
 
     let mut contents = Vec::new();
     contents.push(original_content);
 
     for i in 1..6 {
-        let pw = String::from(PASSWORD);
         let entry_name = format!("entry{}", i);
-        let new_content = add_dummy_entry(&mut file, pw, &entry_name);
+        let new_content = add_dummy_entry(&mut vault, &entry_name);
         contents.push(new_content);
     }
 
     for i in 1..6 {
         let j = 6 - i;
-        let pw = String::from(PASSWORD);
         let entry_name = format!("entry{}", j);
-        let new_content = remove_entry(&mut file, pw, &entry_name);
+        let new_content = remove_entry(&mut vault, &entry_name);
 
         assert!(new_content.len() < contents[j].len());
     }
@@ -161,28 +161,18 @@ fn can_list_entries() {
     delete_file();
     create_file();
 
-    let mut file = read_file();
-
-    let pw = String::from(PASSWORD);
-    let pw_hash = crypto::hash(vec![pw.as_bytes(), &file.head.salt[..]]);
-
-    if pw_hash != file.head.pw_hash {
-        panic!("Wrong password hash.");
-    }
+    let mut vault = unlock_file();
 
     for i in 0..5 {
-        let pw = String::from(PASSWORD);
         let entry_name = format!("entry{}", i);
-        add_dummy_entry(&mut file, pw, &entry_name);
+        add_dummy_entry(&mut vault, &entry_name);
     }
 
-    let list = file.list(pw);
-
-    assert!(list.is_ok());
+    let list = unlock_file().list();
 
     for i in 0..5 {
         let entry_name = format!("entry{}", i);
-        assert!(list.as_ref().unwrap().contains(&entry_name));
+        assert!(list.contains(&entry_name));
     }
 
     delete_file();
@@ -193,24 +183,45 @@ fn can_retrieve_entry() {
     delete_file();
 
     let original_content = create_file();
-    let mut file = read_file();
-
-    let pw = String::from(PASSWORD);
-    let pw_hash = crypto::hash(vec![pw.as_bytes(), &file.head.salt[..]]);
-
-    if pw_hash != file.head.pw_hash {
-        panic!("Wrong password hash.");
-    }
+    let mut vault = unlock_file();
 
     let entry_name = "entry1";
-    let new_content = add_dummy_entry(&mut file, pw, entry_name);
+    let new_content = add_dummy_entry(&mut vault, entry_name);
     assert_ne!(original_content, new_content);
     assert!(new_content.len() > original_content.len());
 
-    let pw = String::from(PASSWORD);
-    let mut file = read_file();
-    let entry = file.get_entry(pw, entry_name).unwrap().unwrap();
-    assert_eq!(entry, get_dummy_entry());
+    let vault = unlock_file();
+    let entry = vault.get(entry_name).unwrap().unwrap();
+    let expected = fman::OpenEntry {
+        created: entry.created,
+        modified: entry.modified,
+        pw_changed: entry.pw_changed,
+        ..get_dummy_entry()
+    };
+    assert_eq!(entry, expected);
+
+    delete_file();
+}
+
+#[test]
+fn entries_have_timestamps() {
+    delete_file();
+    create_file();
+
+    let before = time::now();
+    let mut vault = unlock_file();
+    add_dummy_entry(&mut vault, "entry1");
+
+    let entry = vault.get("entry1").unwrap().unwrap();
+    assert!(entry.created >= before);
+    assert_eq!(entry.created, entry.modified);
+    assert_eq!(entry.created, entry.pw_changed);
+    assert_eq!(entry.last_used, None);
+
+    vault.mark_used("entry1").unwrap();
+    let entries = vault.get_all().unwrap();
+    assert_eq!(entries.len(), 1);
+    assert!(entries[0].1.last_used.unwrap() >= entry.created);
 
     delete_file();
 }
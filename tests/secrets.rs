@@ -0,0 +1,75 @@
+//! Checks that secrets are wiped from memory before it is freed. An allocator
+//! wrapping the system one looks for a marker string in every block being
+//! deallocated.
+
+use guarakapa::{
+    crypto::{SecretBytes, SecretString},
+    fman,
+};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+const MARKER: &str = "kapa-secret-marker-7f3e";
+
+struct ScanningAllocator;
+
+static SCANNING: AtomicBool = AtomicBool::new(false);
+static LEAKS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for ScanningAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if SCANNING.load(Ordering::SeqCst) {
+            let block = std::slice::from_raw_parts(ptr, layout.size());
+            if block.windows(MARKER.len()).any(|w| w == MARKER.as_bytes()) {
+                LEAKS.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: ScanningAllocator = ScanningAllocator;
+
+/// Runs `f` and returns how many freed blocks contained the marker
+fn count_leaks(f: impl FnOnce()) -> usize {
+    LEAKS.store(0, Ordering::SeqCst);
+    SCANNING.store(true, Ordering::SeqCst);
+    f();
+    SCANNING.store(false, Ordering::SeqCst);
+    LEAKS.load(Ordering::SeqCst)
+}
+
+// a single test, since the allocator is shared by all threads of the harness
+#[test]
+fn secrets_are_wiped_before_being_freed() {
+    // the allocator does detect secrets left in plain buffers
+    assert!(count_leaks(|| drop(MARKER.to_string())) > 0);
+
+    assert_eq!(
+        count_leaks(|| drop(SecretBytes::from(MARKER.as_bytes().to_vec()))),
+        0
+    );
+    assert_eq!(
+        count_leaks(|| drop(SecretString::from(MARKER.to_string()))),
+        0
+    );
+
+    let leaks = count_leaks(|| {
+        let file = fman::File::try_new("master").unwrap();
+        let mut vault = file.unlock("master").unwrap();
+        let entry = fman::OpenEntry {
+            pw: MARKER.into(),
+            ..Default::default()
+        };
+        vault.add(String::from("entry"), entry).unwrap();
+
+        let entry = vault.get("entry").unwrap().unwrap();
+        assert_eq!(&*entry.pw, MARKER);
+    });
+    assert_eq!(leaks, 0);
+}